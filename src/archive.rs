@@ -0,0 +1,371 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use color_eyre::eyre::{bail, eyre, Result};
+use log::debug;
+
+/// Tar headers and padded entries are made of 512 byte blocks
+const TAR_BLOCK: usize = 512;
+
+/// Last-modified time of tar entries, the same day as [`ZIP_DOS_DATE`] so that
+/// archives of the same outputs are byte-identical
+const TAR_MTIME: u64 = 315_532_800;
+
+/// Last-modified date of zip entries, 1980-01-01 in MS-DOS format
+const ZIP_DOS_DATE: u16 = (1 << 5) | 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Tar,
+    /// Entries are stored, AVIF data doesn't compress any further
+    Zip,
+}
+
+/// Offset and checksum of a zip entry, needed again for the central directory
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+struct Writer {
+    file: BufWriter<File>,
+    written: u64,
+    names: HashSet<String>,
+    zip_entries: Vec<ZipEntry>,
+}
+
+/// Single `.tar` or `.zip` file collecting the outputs of `--archive`.
+///
+/// Workers append under a mutex, so entries land in the order they finish.
+/// Zip archives are limited to 65535 entries and 4 GiB, tar has no limit.
+pub struct Archive {
+    format: Format,
+    writer: Mutex<Writer>,
+}
+
+impl Archive {
+    pub fn create(path: &Path) -> Result<Self> {
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+
+        let format = match extension.as_deref() {
+            Some("tar") => Format::Tar,
+            Some("zip") => Format::Zip,
+            _ => bail!("{} is not a .tar or .zip file", path.display()),
+        };
+
+        Ok(Self {
+            format,
+            writer: Mutex::new(Writer {
+                file: BufWriter::new(File::create(path)?),
+                written: 0,
+                names: HashSet::new(),
+                zip_entries: Vec::new(),
+            }),
+        })
+    }
+
+    /// Add `data` as `name`. Returns `false` without writing anything
+    /// when the archive already has an entry with that name
+    pub fn append(&self, name: &str, data: &[u8]) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+
+        if writer.names.contains(name) {
+            debug!("{name} is already archived");
+            return Ok(false);
+        }
+
+        match self.format {
+            Format::Tar => writer.append_tar(name, data)?,
+            Format::Zip => writer.append_zip(name, data)?,
+        }
+
+        writer.names.insert(name.to_string());
+
+        Ok(true)
+    }
+
+    /// Write the trailer. Without it the archive is truncated
+    pub fn finish(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+
+        match self.format {
+            Format::Tar => writer.write(&[0; TAR_BLOCK * 2])?,
+            Format::Zip => writer.write_zip_directory()?,
+        }
+
+        writer.file.flush()?;
+
+        Ok(())
+    }
+}
+
+impl Writer {
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.file.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+
+        Ok(())
+    }
+
+    fn append_tar(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let (prefix, name) = split_tar_name(name)?;
+
+        // ustar header, numbers are zero padded octal
+        let mut header = [0u8; TAR_BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[108..116].copy_from_slice(b"0000000\0");
+        header[116..124].copy_from_slice(b"0000000\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[136..148].copy_from_slice(format!("{TAR_MTIME:011o}\0").as_bytes());
+        header[148..156].copy_from_slice(b"        ");
+        header[156] = b'0';
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+        self.write(&header)?;
+        self.write(data)?;
+
+        let padding = (TAR_BLOCK - data.len() % TAR_BLOCK) % TAR_BLOCK;
+        self.write(&[0; TAR_BLOCK][..padding])
+    }
+
+    fn append_zip(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        if self.zip_entries.len() >= usize::from(u16::MAX) {
+            bail!("Zip archives hold at most 65535 files, use a .tar archive instead")
+        }
+
+        let (Ok(offset), Ok(size)) = (u32::try_from(self.written), u32::try_from(data.len()))
+        else {
+            bail!("Zip archives are limited to 4 GiB, use a .tar archive instead")
+        };
+
+        let crc = crc32fast::hash(data);
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend(0x04034b50u32.to_le_bytes());
+        header.extend(zip_common_fields(crc, size, name));
+        header.extend(0u16.to_le_bytes());
+        header.extend(name.as_bytes());
+
+        self.write(&header)?;
+        self.write(data)?;
+
+        self.zip_entries.push(ZipEntry {
+            name: name.to_string(),
+            crc,
+            size,
+            offset,
+        });
+
+        Ok(())
+    }
+
+    fn write_zip_directory(&mut self) -> Result<()> {
+        let start = self.written;
+
+        for entry in std::mem::take(&mut self.zip_entries).iter() {
+            let mut record = Vec::with_capacity(46 + entry.name.len());
+            record.extend(0x02014b50u32.to_le_bytes());
+            // Version made by
+            record.extend(20u16.to_le_bytes());
+            record.extend(zip_common_fields(entry.crc, entry.size, &entry.name));
+            // Extra field, comment, disk number, internal and external attributes
+            record.extend([0; 12]);
+            record.extend(entry.offset.to_le_bytes());
+            record.extend(entry.name.as_bytes());
+
+            self.write(&record)?;
+        }
+
+        let entries = self.names.len() as u16;
+        let (Ok(directory_size), Ok(directory_start)) =
+            (u32::try_from(self.written - start), u32::try_from(start))
+        else {
+            bail!("Zip archives are limited to 4 GiB, use a .tar archive instead")
+        };
+
+        let mut end = Vec::with_capacity(22);
+        end.extend(0x06054b50u32.to_le_bytes());
+        // This disk and the disk where the directory starts
+        end.extend([0; 4]);
+        end.extend(entries.to_le_bytes());
+        end.extend(entries.to_le_bytes());
+        end.extend(directory_size.to_le_bytes());
+        end.extend(directory_start.to_le_bytes());
+        // Comment length
+        end.extend([0; 2]);
+
+        self.write(&end)
+    }
+}
+
+/// Fields shared by the local header and the central directory, up to the name length
+fn zip_common_fields(crc: u32, size: u32, name: &str) -> Vec<u8> {
+    let mut fields = Vec::with_capacity(24);
+    // Version needed to extract
+    fields.extend(20u16.to_le_bytes());
+    // Names are UTF-8
+    fields.extend(0x0800u16.to_le_bytes());
+    // Stored, no compression
+    fields.extend(0u16.to_le_bytes());
+    fields.extend(0u16.to_le_bytes());
+    fields.extend(ZIP_DOS_DATE.to_le_bytes());
+    fields.extend(crc.to_le_bytes());
+    fields.extend(size.to_le_bytes());
+    fields.extend(size.to_le_bytes());
+    fields.extend((name.len() as u16).to_le_bytes());
+    fields
+}
+
+/// ustar keeps names of up to 100 bytes, longer paths put their directories in a
+/// 155 byte prefix
+fn split_tar_name(name: &str) -> Result<(&str, &str)> {
+    if name.len() <= 100 {
+        return Ok(("", name));
+    }
+
+    name.match_indices('/')
+        .map(|(index, _)| (&name[..index], &name[index + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+        .ok_or_else(|| eyre!("{name} is too long for a tar archive"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, process};
+
+    use super::*;
+
+    fn archive_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("avif-converter-{}-{name}", process::id()))
+    }
+
+    fn write_archive(path: &Path, entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let archive = Archive::create(path).unwrap();
+
+        for (name, data) in entries {
+            assert!(archive.append(name, data).unwrap());
+        }
+        assert!(!archive.append(entries[0].0, b"again").unwrap());
+        archive.finish().unwrap();
+
+        let bytes = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+        bytes
+    }
+
+    fn octal(field: &[u8]) -> usize {
+        let digits = std::str::from_utf8(field).unwrap();
+        usize::from_str_radix(digits.trim_matches(|c| c == '\0' || c == ' '), 8).unwrap()
+    }
+
+    fn le16(bytes: &[u8], at: usize) -> usize {
+        usize::from(u16::from_le_bytes([bytes[at], bytes[at + 1]]))
+    }
+
+    fn le32(bytes: &[u8], at: usize) -> usize {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize
+    }
+
+    /// Long enough to need the ustar prefix
+    fn long_name() -> String {
+        format!("{}/{}.avif", "dir".repeat(40), "b".repeat(60))
+    }
+
+    #[test]
+    fn tar_round_trip() {
+        let long = long_name();
+        let entries: [(&str, &[u8]); 2] = [("a.avif", b"first"), (&long, &[7; 600])];
+        let bytes = write_archive(&archive_path("round.tar"), &entries);
+
+        let mut offset = 0;
+        for (name, data) in entries {
+            let header = &bytes[offset..offset + TAR_BLOCK];
+
+            let checksum: usize = header
+                .iter()
+                .enumerate()
+                .map(|(i, &byte)| {
+                    if (148..156).contains(&i) {
+                        32
+                    } else {
+                        usize::from(byte)
+                    }
+                })
+                .sum();
+            assert_eq!(octal(&header[148..155]), checksum);
+
+            let field = |range: std::ops::Range<usize>| {
+                let field = &header[range];
+                let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+                std::str::from_utf8(&field[..end]).unwrap().to_string()
+            };
+            let prefix = field(345..500);
+            let full_name = match prefix.is_empty() {
+                true => field(0..100),
+                false => format!("{prefix}/{}", field(0..100)),
+            };
+            assert_eq!(full_name, name);
+            assert_eq!(octal(&header[136..147]), TAR_MTIME as usize);
+
+            let size = octal(&header[124..135]);
+            offset += TAR_BLOCK;
+            assert_eq!(&bytes[offset..offset + size], data);
+            offset += size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+        }
+
+        assert_eq!(&bytes[offset..], &[0; TAR_BLOCK * 2]);
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        let entries: [(&str, &[u8]); 2] = [("a.avif", b"first"), ("sub/b.avif", &[7; 600])];
+        let bytes = write_archive(&archive_path("round.zip"), &entries);
+
+        let end = bytes.len() - 22;
+        assert_eq!(le32(&bytes, end), 0x06054b50);
+        assert_eq!(le16(&bytes, end + 10), entries.len());
+
+        let mut record = le32(&bytes, end + 16);
+        for (name, data) in entries {
+            assert_eq!(le32(&bytes, record), 0x02014b50);
+            let name_len = le16(&bytes, record + 28);
+            assert_eq!(&bytes[record + 46..record + 46 + name_len], name.as_bytes());
+
+            let local = le32(&bytes, record + 42);
+            assert_eq!(le32(&bytes, local), 0x04034b50);
+            assert_eq!(le32(&bytes, local + 14), crc32fast::hash(data) as usize);
+
+            let size = le32(&bytes, local + 18);
+            let start = local + 30 + le16(&bytes, local + 26) + le16(&bytes, local + 28);
+            assert_eq!(&bytes[start..start + size], data);
+
+            record += 46 + name_len;
+        }
+    }
+
+    #[test]
+    fn archives_are_reproducible() {
+        for name in ["same.tar", "same.zip"] {
+            let entries: [(&str, &[u8]); 1] = [("a.avif", b"data")];
+
+            assert_eq!(
+                write_archive(&archive_path(name), &entries),
+                write_archive(&archive_path(name), &entries)
+            );
+        }
+    }
+}
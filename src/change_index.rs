@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+use log::{debug, error};
+
+use crate::image_file::ImageFile;
+
+/// Hidden file kept in every source directory by `--encode-only-changed`
+const INDEX_FILE: &str = ".avifconvert-index";
+
+/// Size and modification time (nanoseconds since the epoch) of a source file
+type Stamp = (u64, u128);
+
+/// Size and mtime of the sources converted by earlier runs, one index per directory.
+///
+/// Nothing is hashed, so an edit that keeps both the size and the mtime goes unnoticed.
+/// Later lines win, a changed file is simply appended again.
+#[derive(Debug, Default)]
+pub struct ChangeIndex {
+    dirs: Mutex<HashMap<PathBuf, HashMap<String, Stamp>>>,
+}
+
+impl ChangeIndex {
+    /// Whether `image` has the same size and mtime as when it was last converted
+    pub fn is_unchanged(&self, image: &ImageFile) -> bool {
+        let Some(stamp) = Self::stamp(&image.metadata.path) else {
+            return false;
+        };
+
+        let dir = Self::dir(image);
+        let mut dirs = self.dirs.lock().unwrap();
+        let entries = dirs.entry(dir.clone()).or_insert_with(|| Self::load(&dir));
+
+        entries.get(&Self::key(image)) == Some(&stamp)
+    }
+
+    /// Record the current size and mtime of `image` in its directory's index
+    pub fn mark_converted(&self, image: &ImageFile) {
+        let Some((size, mtime)) = Self::stamp(&image.metadata.path) else {
+            return;
+        };
+
+        // Serialize appends, several workers may share a directory
+        let _dirs = self.dirs.lock().unwrap();
+
+        let path = Self::dir(image).join(INDEX_FILE);
+        let line = format!("{size}\t{mtime}\t{}", Self::key(image));
+
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{line}"));
+
+        if let Err(err) = written {
+            error!("Failed to update {path:?}: {err}");
+        }
+    }
+
+    fn load(dir: &Path) -> HashMap<String, Stamp> {
+        let Ok(contents) = fs::read_to_string(dir.join(INDEX_FILE)) else {
+            return HashMap::new();
+        };
+
+        let entries: HashMap<String, Stamp> = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                let size = fields.next()?.parse().ok()?;
+                let mtime = fields.next()?.parse().ok()?;
+                Some((fields.next()?.to_string(), (size, mtime)))
+            })
+            .collect();
+
+        debug!("Loaded {} indexed files from {dir:?}", entries.len());
+
+        entries
+    }
+
+    fn stamp(path: &Path) -> Option<Stamp> {
+        let metadata = fs::metadata(path).ok()?;
+        let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+        Some((metadata.len(), mtime.as_nanos()))
+    }
+
+    fn dir(image: &ImageFile) -> PathBuf {
+        match image.metadata.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    }
+
+    /// File name, plus the page for multi-page TIFFs
+    fn key(image: &ImageFile) -> String {
+        match image.page {
+            Some(page) => format!("{}#{page}", image.metadata.filename),
+            None => image.metadata.filename.clone(),
+        }
+    }
+}
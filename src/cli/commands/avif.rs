@@ -1,18 +1,45 @@
-use std::{path::PathBuf, process::exit, sync::atomic::Ordering, time::Instant};
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::atomic::Ordering,
+    time::Instant,
+};
+
+use std::sync::{Arc, Mutex};
 
 use bytesize::ByteSize;
-use clap::Args;
-use color_eyre::eyre::bail;
-use log::{debug, trace};
+use clap::{Args, ValueEnum};
+use color_eyre::eyre::{bail, eyre, Report};
+use indicatif::ProgressBar;
+use log::{debug, error, trace};
 use owo_colors::OwoColorize;
+use rand::{seq::SliceRandom, thread_rng};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use threadpool::ThreadPool;
 
 use crate::{
-    cli::{Args as Globals, FINAL_STATS, ITEMS_PROCESSED, SUCCESS_COUNT},
-    console::ConsoleMsg,
-    image_file::ImageFile,
-    utils::{calculate_tread_count, parse_files, sys_threads, PROGRESS_BAR},
+    archive::Archive,
+    change_index::ChangeIndex,
+    cli::{
+        Args as Globals, ProgressFormat, DEDUP_COUNT, DEDUP_STATS, FAILED_COUNT, FINAL_STATS,
+        GROWN_COUNT, GROWN_STATS, ITEMS_PROCESSED, LIMIT_SLOTS, SOURCE_STATS, SUCCESS_COUNT,
+        TIMED_OUT_COUNT,
+    },
+    config::DirConfig,
+    console::{BatchSummary, ConsoleMsg, CsvRow, FailedFile, ProgressEvent, Verbosity},
+    encoders::avif::error::Error as EncodeError,
+    encoders::formats::{encode_jpeg, encode_webp},
+    image_file::{EncodeOptions, ImageFile, SaveOptions, Variant},
+    resume::ResumeState,
+    utils::{
+        calculate_tread_count, is_avif, parse_files, run_exec_hook, sys_threads, PROGRESS_BAR,
+    },
 };
+
+#[cfg(feature = "ssim")]
+use crate::ssim::apply_colormap;
 use color_eyre::Result;
 
 use super::EncodeFuncs;
@@ -38,6 +65,146 @@ pub struct Avif {
     #[clap(short, long, conflicts_with = "name_type", value_name = "OUTPUT")]
     pub output_file: Option<PathBuf>,
 
+    /// Also encode to WebP and JPEG at the same `--quality` and report each format's size
+    /// instead of saving the AVIF. A format that fails to encode is reported and skipped
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with = "benchmark",
+        conflicts_with = "output_file"
+    )]
+    pub compare_formats: bool,
+
+    /// Only decode every input and report the ones that fail, nothing is encoded or saved
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with = "benchmark",
+        conflicts_with = "compare_formats"
+    )]
+    pub dry_validate: bool,
+
+    /// Encode a random sample of the inputs at several qualities and estimate the size of the
+    /// whole set at each one. Uses `--quality-set` if given, nothing is written
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with = "benchmark",
+        conflicts_with = "compare_formats",
+        conflicts_with = "dry_validate"
+    )]
+    pub stats_only: bool,
+
+    /// How many files `--stats-only` encodes
+    #[clap(
+        long,
+        default_value_t = 20,
+        value_name = "COUNT",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "stats_only"
+    )]
+    pub sample: u64,
+
+    /// Save every format encoded by `--compare-formats` next to the original
+    #[clap(long, default_value_t = false, requires = "compare_formats")]
+    pub compare_save: bool,
+
+    /// Encode one output per quality instead of using `--quality`, named `<name>_q<QUALITY>`.
+    /// The source is decoded once and always kept
+    #[clap(
+        long,
+        value_name = "QUALITY",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        conflicts_with = "output_file"
+    )]
+    pub quality_set: Vec<u8>,
+
+    /// Encode one downscaled output per width, named `<name>_<WIDTH>w`. Widths larger
+    /// than the source are skipped. The source is decoded once and always kept,
+    /// along with the last downscale, which the next narrower width starts from
+    #[clap(
+        long,
+        value_name = "WIDTH",
+        value_delimiter = ',',
+        value_parser = clap::value_parser!(u32).range(32..),
+        conflicts_with = "output_file"
+    )]
+    pub widths: Vec<u32>,
+
+    /// Write one row per output to PATH: name, dimensions, sizes, ratio, quality and SSIM
+    /// when `--ssim` is on
+    #[clap(long, value_name = "PATH", conflicts_with = "output_file")]
+    pub csv: Option<PathBuf>,
+
+    /// Print the final summary to stdout in a machine readable format instead of text
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    pub stdout_summary: Option<SummaryFormat>,
+
+    /// Stop at the first file that fails to convert or save and exit with an error.
+    /// Files already being encoded finish, the rest are skipped
+    #[clap(long, default_value_t = false)]
+    pub abort_on_first_error: bool,
+
+    /// Cap the encoder threads each file gets in a batch. With fewer files than threads
+    /// every file otherwise gets an equal share of all of them. rav1e also won't split
+    /// small images into tiles below the speed preset's minimum tile size, so threads
+    /// past that tile count mostly sit idle
+    #[clap(
+        long,
+        value_name = "THREADS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_threads_per_image: Option<u64>,
+
+    /// Exit with an error if any file got bigger after conversion
+    #[clap(long, default_value_t = false)]
+    pub fail_on_growth: bool,
+
+    /// Record finished files in STATE and skip them when the same job is run again
+    #[clap(long, value_name = "STATE", conflicts_with = "benchmark")]
+    pub resume: Option<PathBuf>,
+
+    /// Skip sources whose size and mtime match the last conversion, tracked in a hidden
+    /// `.avifconvert-index` in each directory. Edits that keep both aren't detected
+    #[clap(long, default_value_t = false, conflicts_with = "benchmark")]
+    pub encode_only_changed: bool,
+
+    /// Write every output into a single `.tar` or `.zip` file instead of loose files.
+    /// Sources are always kept. Zip archives are limited to 65535 files and 4 GiB
+    #[clap(
+        long,
+        value_name = "ARCHIVE",
+        conflicts_with = "benchmark",
+        conflicts_with = "output_file"
+    )]
+    pub archive: Option<PathBuf>,
+
+    /// Keep the directories of the sources in the archive, relative to the deepest folder
+    /// holding every input, instead of putting every output at the top
+    #[clap(long, default_value_t = false, requires = "archive")]
+    pub preserve_structure: bool,
+
+    /// Order in which files are queued for encoding
+    #[clap(long, value_enum, default_value_t = SortOrder::Name)]
+    pub sort: SortOrder,
+
+    /// Decode `.avif` inputs and encode them again with the current settings.
+    /// This is lossy-on-lossy, every pass throws away more detail
+    #[clap(long, default_value_t = false)]
+    pub reencode: bool,
+
+    /// Re-encode `.avif` files found while scanning directories. They are skipped by default
+    /// so re-running over a partially converted folder only picks up new images
+    #[clap(long, default_value_t = false, requires = "reencode")]
+    pub include_avif: bool,
+
+    /// Save the center of the source and of the encoded image side by side as
+    /// `<name>_preview.png`, to check for artifacts before converting a whole batch.
+    /// Single file mode only
+    #[clap(long, default_value_t = false)]
+    pub preview: bool,
+
     /// Send a notification to the desktop when all jobs are finished
     #[clap(short = 'N', long, default_value_t = false)]
     pub notify: bool,
@@ -51,16 +218,602 @@ pub struct Avif {
     #[cfg(feature = "ssim")]
     #[clap(long = "ssim_save", default_value_t = false, requires = "ssim")]
     pub ssim_save: bool,
+
+    /// Downscale both images by this factor before measuring SSIM.
+    /// Much faster on huge images, but the absolute SSIM value shifts slightly.
+    #[cfg(feature = "ssim")]
+    #[clap(
+        long = "ssim_scale",
+        default_value_t = 1.0,
+        value_parser(ssim_scale_values),
+        requires = "ssim"
+    )]
+    pub ssim_scale: f32,
+
+    /// Report every file whose SSIM is below this value
+    #[cfg(feature = "ssim")]
+    #[clap(
+        long,
+        value_name = "SSIM",
+        value_parser(ssim_threshold_values),
+        requires = "ssim"
+    )]
+    pub ssim_threshold: Option<f64>,
+
+    /// Exit with an error when any file falls below `--ssim-threshold`
+    #[cfg(feature = "ssim")]
+    #[clap(long, default_value_t = false, requires = "ssim_threshold")]
+    pub fail_below_ssim: bool,
+}
+
+/// Qualities tried by `--stats-only` when no `--quality-set` is given
+const STATS_QUALITIES: [u8; 5] = [50, 60, 70, 80, 90];
+
+/// Lowest average SSIM `--stats-only` recommends when no `--ssim-threshold` is given
+#[cfg(feature = "ssim")]
+const STATS_MIN_SSIM: f64 = 0.95;
+
+/// Side of the square crops in a `--preview` image
+const PREVIEW_SIZE: u32 = 512;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum SummaryFormat {
+    /// A single JSON object
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+    /// Alphabetical by file name
+    Name,
+    /// Smallest files first, for quick early progress
+    Size,
+    /// Largest files first, surfaces the big wins early and balances threads better
+    SizeDesc,
+    /// Oldest modification time first
+    Mtime,
+}
+
+impl SortOrder {
+    fn apply(self, files: &mut [ImageFile]) {
+        match self {
+            SortOrder::Name => files.sort_by(|a, b| a.metadata.name.cmp(&b.metadata.name)),
+            SortOrder::Size => files.sort_by_key(|file| file.metadata.size),
+            SortOrder::SizeDesc => files.sort_by_key(|file| Reverse(file.metadata.size)),
+            SortOrder::Mtime => files.sort_by_cached_key(|file| {
+                fs::metadata(&file.metadata.path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            }),
+        }
+    }
+}
+
+/// One entry per output of a source, see `--quality-set` and `--widths`.
+/// Widths larger than the source are skipped, the rest go from widest to narrowest
+/// so each downscale can start from the previous one.
+fn variants(
+    quality_set: &[u8],
+    widths: &[u32],
+    options: EncodeOptions,
+    source_width: u32,
+) -> Vec<Variant> {
+    let qualities: Vec<Option<u8>> = if quality_set.is_empty() {
+        vec![None]
+    } else {
+        quality_set.iter().copied().map(Some).collect()
+    };
+
+    let widths: Vec<Option<u32>> = if widths.is_empty() {
+        vec![None]
+    } else {
+        let mut widths: Vec<u32> = widths
+            .iter()
+            .copied()
+            .filter(|&width| width <= source_width)
+            .collect();
+        widths.sort_unstable_by(|a, b| b.cmp(a));
+        widths.dedup();
+        widths.into_iter().map(Some).collect()
+    };
+
+    widths
+        .iter()
+        .flat_map(|&width| qualities.iter().map(move |&quality| (width, quality)))
+        .map(|(width, quality)| {
+            let suffix = width.map(|w| format!("_{w}w")).unwrap_or_default()
+                + &quality.map(|q| format!("_q{q}")).unwrap_or_default();
+
+            Variant {
+                suffix: (!suffix.is_empty()).then_some(suffix),
+                options: EncodeOptions {
+                    quality: quality.unwrap_or(options.quality),
+                    width,
+                    ..options.clone()
+                },
+            }
+        })
+        .collect()
+}
+
+/// Name of `item`'s output inside `--archive`. With a `root`, the entry keeps the
+/// source's directory relative to it
+fn archive_entry_name(item: &ImageFile, options: &SaveOptions, root: Option<&Path>) -> String {
+    let file_name = item.output_file_name(options);
+
+    let dir = root.and_then(|root| item.metadata.path.parent()?.strip_prefix(root).ok());
+
+    match dir.filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => format!("{}/{file_name}", archive_path(dir)),
+        None => file_name,
+    }
+}
+
+/// Deepest directory holding every item
+fn common_root(items: &[ImageFile]) -> PathBuf {
+    let mut dirs = items.iter().filter_map(|item| item.metadata.path.parent());
+
+    let Some(first) = dirs.next() else {
+        return PathBuf::new();
+    };
+
+    dirs.fold(first.to_path_buf(), |root, dir| {
+        root.components()
+            .zip(dir.components())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a)
+            .collect()
+    })
+}
+
+/// Archives always separate directories with `/`
+fn archive_path(dir: &Path) -> String {
+    dir.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// A corrupt file shouldn't take the whole batch down, log it and move on
+fn skip_failed(
+    item: &ImageFile,
+    err: &Report,
+    bar: Option<ProgressBar>,
+    json_total: Option<u64>,
+    failures: &Mutex<Vec<FailedFile>>,
+) {
+    error!("Failed to convert {}: {err}", item.original_name());
+    let reason = err.to_string();
+    if let Some(bar) = bar {
+        bar.inc(1);
+    }
+    let done = ITEMS_PROCESSED.fetch_add(1, Ordering::SeqCst) + 1;
+    FAILED_COUNT.fetch_add(1, Ordering::SeqCst);
+    // Only successful sources count towards `--limit`
+    LIMIT_SLOTS.fetch_sub(1, Ordering::SeqCst);
+
+    if let Some(total) = json_total {
+        ProgressEvent {
+            done,
+            total,
+            file: &item.metadata.filename,
+            color_bytes: 0,
+            alpha_bytes: 0,
+            timings: item.timings,
+            error: Some(&reason),
+        }
+        .emit();
+    }
+
+    failures.lock().unwrap().push(FailedFile {
+        path: item.metadata.path.clone(),
+        reason,
+    });
+
+    if let Some(EncodeError::TimedOut(_)) = err.downcast_ref::<EncodeError>() {
+        TIMED_OUT_COUNT.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Red list of every failed file and the reason, sorted by path
+fn print_failures(con: &ConsoleMsg, failures: &[FailedFile]) {
+    if failures.is_empty() {
+        return;
+    }
+
+    con.print_summary(format!("{} files failed:", failures.len().red().bold()));
+
+    for failure in failures {
+        con.print_summary(format!(
+            "  {}: {}",
+            failure.path.display(),
+            failure.reason.red()
+        ));
+    }
+}
+
+/// Measure the SSIM of the output `item` holds and add it to `scores` under the
+/// source name and the output suffix. With `save_diff` the difference image is saved
+/// next to the source. Failures are only logged
+#[cfg(feature = "ssim")]
+fn record_ssim(
+    item: &ImageFile,
+    scale: f32,
+    save_diff: bool,
+    scores: &Mutex<Vec<(String, f64)>>,
+) -> Option<f64> {
+    let name = format!(
+        "{}{}",
+        item.original_name(),
+        item.suffix.as_deref().unwrap_or_default()
+    );
+
+    let (score, diff) = match item.measure_ssim(scale) {
+        Ok(measured) => measured,
+        Err(err) => {
+            error!("Failed to measure SSIM of {name}: {err}");
+            return None;
+        }
+    };
+
+    debug!("SSIM of {name}: {score:.4}");
+
+    if save_diff {
+        if let Err(err) = apply_colormap(&diff).save(item.ssim_diff_path()) {
+            error!("Failed to save SSIM difference for {name}: {err}");
+        }
+    }
+
+    scores.lock().unwrap().push((name, score));
+
+    Some(score)
+}
+
+#[cfg(feature = "ssim")]
+fn ssim_scale_values(s: &str) -> Result<f32, String> {
+    let scale: f32 = s
+        .parse()
+        .map_err(|_| format!("`{s}` isn't a valid number"))?;
+
+    if scale > 0. && scale <= 1. {
+        Ok(scale)
+    } else {
+        Err("SSIM scale must be in the (0, 1] range".to_string())
+    }
+}
+
+#[cfg(feature = "ssim")]
+fn ssim_threshold_values(s: &str) -> Result<f64, String> {
+    let threshold: f64 = s
+        .parse()
+        .map_err(|_| format!("`{s}` isn't a valid number"))?;
+
+    if (0. ..=1.).contains(&threshold) {
+        Ok(threshold)
+    } else {
+        Err("SSIM threshold must be in the [0, 1] range".to_string())
+    }
+}
+
+impl Avif {
+    /// Decode every input in parallel and list the files that would fail to convert
+    fn dry_validate(self, console: ConsoleMsg, globals: &Globals) -> Result<()> {
+        let mut console = console;
+        console.set_spinner("Searching for files...");
+
+        let paths = parse_files(&self.path, self.include_avif, globals.input_format());
+        let psize = paths.len();
+
+        console.set_spinner(format!("Decoding {psize} files..."));
+
+        let mut failures: Vec<(String, String)> = paths
+            .into_par_iter()
+            .filter_map(|mut image| {
+                image
+                    .load_image_data(globals.background(), false, globals.io_retries)
+                    .err()
+                    .map(|err| (image.original_name(), err.to_string()))
+            })
+            .collect();
+
+        failures.sort();
+
+        let console = console.finish_spinner(&format!("Decoded {psize} files."));
+
+        for (name, reason) in &failures {
+            console.print_summary(format!("{} {name}: {reason}", "✗".red().bold()));
+        }
+
+        console.print_summary(format!(
+            "{} decodable | {} undecodable",
+            (psize - failures.len()).green().bold(),
+            failures.len().red().bold()
+        ));
+
+        if !failures.is_empty() {
+            exit(1);
+        }
+
+        Ok(())
+    }
+
+    /// Encode a random sample at a few qualities and extrapolate the savings to every input
+    fn stats_only(self, console: ConsoleMsg, globals: &Globals) -> Result<()> {
+        let mut console = console;
+        console.set_spinner("Searching for files...");
+
+        let mut paths = parse_files(&self.path, self.include_avif, globals.input_format());
+        let total = paths.len();
+        let total_size: u64 = paths.iter().map(|image| image.metadata.size).sum();
+
+        if total == 0 {
+            bail!("No files to sample")
+        }
+
+        paths.shuffle(&mut thread_rng());
+        paths.truncate(self.sample as usize);
+
+        let qualities = if self.quality_set.is_empty() {
+            STATS_QUALITIES.to_vec()
+        } else {
+            self.quality_set.clone()
+        };
+
+        // Encoded bytes and summed SSIM per quality
+        let mut encoded = vec![0u64; qualities.len()];
+        #[cfg(feature = "ssim")]
+        let mut ssim = vec![0f64; qualities.len()];
+        let mut sample_size = 0;
+        let mut sampled = 0;
+
+        let sample_len = paths.len();
+
+        for (i, mut image) in paths.into_iter().enumerate() {
+            console.set_spinner(format!("Encoding sample {}/{sample_len}...", i + 1));
+
+            let globals = globals.with_dir_config(image.dir_config.as_ref());
+            let options = globals.encode_options(sys_threads(globals.threads));
+
+            let results: Result<Vec<_>> = qualities
+                .iter()
+                .map(|&quality| {
+                    let size = image.convert_to_avif_stored(
+                        &EncodeOptions {
+                            quality,
+                            ..options.clone()
+                        },
+                        None,
+                    )?;
+
+                    #[cfg(feature = "ssim")]
+                    let score = image.measure_ssim(self.ssim_scale)?.0;
+                    #[cfg(not(feature = "ssim"))]
+                    let score = 0.;
+
+                    Ok((size, score))
+                })
+                .collect();
+
+            match results {
+                Ok(results) => {
+                    #[cfg_attr(not(feature = "ssim"), allow(unused_variables))]
+                    for (q, (size, score)) in results.into_iter().enumerate() {
+                        encoded[q] += size;
+                        #[cfg(feature = "ssim")]
+                        {
+                            ssim[q] += score;
+                        }
+                    }
+
+                    sample_size += image.metadata.size;
+                    sampled += 1;
+                }
+                Err(err) => error!("Failed to sample {}: {err}", image.original_name()),
+            }
+        }
+
+        let console = console.finish_spinner(&format!(
+            "Sampled {sampled} of {total} files ({} of {}).",
+            ByteSize::b(sample_size).to_string_as(true),
+            ByteSize::b(total_size).to_string_as(true)
+        ));
+
+        if sampled == 0 {
+            bail!("None of the sampled files could be encoded")
+        }
+
+        let mut table = format!("{:<8}{:>14}{:>10}", "Quality", "Est. size", "Ratio");
+
+        #[cfg(feature = "ssim")]
+        table.push_str(&format!("{:>10}", "SSIM"));
+
+        for (q, quality) in qualities.iter().enumerate() {
+            let ratio = encoded[q] as f64 / sample_size as f64;
+
+            table.push_str(&format!(
+                "\n{:<8}{:>14}{:>9.1}%",
+                quality,
+                ByteSize::b((total_size as f64 * ratio) as u64).to_string_as(true),
+                ratio * 100.
+            ));
+
+            #[cfg(feature = "ssim")]
+            table.push_str(&format!("{:>10.4}", ssim[q] / sampled as f64));
+        }
+
+        console.print_summary(table);
+
+        #[cfg(feature = "ssim")]
+        {
+            let min_ssim = self.ssim_threshold.unwrap_or(STATS_MIN_SSIM);
+
+            let recommended = qualities
+                .iter()
+                .zip(&ssim)
+                .filter(|(_, score)| *score / sampled as f64 >= min_ssim)
+                .min_by_key(|(quality, _)| **quality);
+
+            match recommended {
+                Some((quality, _)) => console.print_summary(format!(
+                    "Lowest quality with an average SSIM of at least {min_ssim}: {}",
+                    quality.green().bold()
+                )),
+                None => console.print_summary(format!(
+                    "No tried quality reaches an average SSIM of {min_ssim}"
+                )),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encode each file to AVIF, WebP and JPEG at the same quality and print a size table
+    fn compare_formats(self, console: ConsoleMsg, globals: &Globals) -> Result<()> {
+        let mut console = console;
+        console.set_spinner("Searching for files...");
+
+        let paths = parse_files(&self.path, self.include_avif, globals.input_format());
+
+        let console = console.finish_spinner(&format!("Found {} files.", paths.len()));
+
+        let mut failed = 0;
+
+        for mut image in paths {
+            let globals = globals.with_dir_config(image.dir_config.as_ref());
+            let original_size = image.metadata.size;
+
+            let options = globals.encode_options(sys_threads(globals.threads));
+            if let Err(err) = image.prepare(&options) {
+                console.print_summary(format!(
+                    "{} {}: {err}",
+                    "✗".red().bold(),
+                    image.original_name()
+                ));
+                failed += 1;
+                continue;
+            }
+
+            let outputs = [
+                (
+                    "avif",
+                    image
+                        .convert_to_avif_stored(&options, None)
+                        .map(|_| image.encoded_data.clone()),
+                ),
+                ("webp", encode_webp(&image.bitmap, globals.quality)),
+                ("jpg", encode_jpeg(&image.bitmap, globals.quality)),
+            ];
+
+            let mut table = format!(
+                "{} ({})\n{:<6}{:>12}{:>10}",
+                image.original_name().bold(),
+                ByteSize::b(original_size).to_string_as(true).blue(),
+                "Format",
+                "Size",
+                "Ratio"
+            );
+
+            #[cfg(feature = "ssim")]
+            table.push_str(&format!("{:>10}", "SSIM"));
+
+            for (ext, data) in &outputs {
+                let data = match data {
+                    Ok(data) => data,
+                    Err(err) => {
+                        error!("Failed to encode {} as {ext}: {err}", image.original_name());
+                        table.push_str(&format!("\n{:<6}{:>12}", ext.to_uppercase(), "failed"));
+                        continue;
+                    }
+                };
+
+                let ratio = data.len() as f64 / original_size as f64 * 100.;
+
+                table.push_str(&format!(
+                    "\n{:<6}{:>12}{:>9.1}%",
+                    ext.to_uppercase(),
+                    ByteSize::b(data.len() as u64).to_string_as(true),
+                    ratio
+                ));
+
+                #[cfg(feature = "ssim")]
+                if self.ssim {
+                    let decoded = match *ext {
+                        "avif" => crate::decoders::avif::decode_avif_bytes(data)?,
+                        _ => image::load_from_memory(data)?,
+                    };
+                    let (score, _) =
+                        crate::ssim::measure_ssim(&image.bitmap, &decoded, self.ssim_scale);
+                    table.push_str(&format!("{score:>10.4}"));
+                }
+
+                if self.compare_save {
+                    let path = image
+                        .metadata
+                        .path
+                        .with_file_name(format!("{}_compare.{ext}", image.metadata.name));
+                    fs::write(&path, data)?;
+                    debug!("Saved {}", path.display());
+                }
+            }
+
+            console.print_summary(table);
+        }
+
+        if failed > 0 {
+            bail!("{failed} files could not be decoded");
+        }
+
+        Ok(())
+    }
 }
 
 impl EncodeFuncs for Avif {
     fn run_conv(self, globals: &Globals) -> Result<()> {
-        let console = ConsoleMsg::new(globals.quiet, self.notify);
-        let error_con = ConsoleMsg::new(globals.quiet, self.notify);
+        let console = ConsoleMsg::new(globals.verbosity(), self.notify);
+        let error_con = ConsoleMsg::new(globals.verbosity(), self.notify);
+
+        if self.reencode {
+            console.print_message(format!(
+                "{} re-encoding AVIF inputs compounds the losses of the first encode",
+                "Warning:".yellow().bold()
+            ));
+        } else if let Some(avif) = self
+            .path
+            .iter()
+            .find(|path| path.is_file() && is_avif(path))
+        {
+            bail!(
+                "{} is already an AVIF, pass --reencode to encode it again",
+                avif.display()
+            )
+        }
+
+        if self.compare_formats {
+            return self.compare_formats(console, globals);
+        }
+
+        if self.dry_validate {
+            return self.dry_validate(console, globals);
+        }
+
+        if self.stats_only {
+            return self.stats_only(console, globals);
+        }
 
         let l_size = self.path.len();
 
-        let u = if l_size > 1 {
+        // A multi-page TIFF produces several outputs, so it goes through the batch path
+        let multi_page = l_size == 1
+            && ImageFile::open(&self.path[0], globals.input_format())
+                .is_ok_and(|image| image.page_count() > 1);
+
+        let u = if l_size > 1
+            || multi_page
+            || self.resume.is_some()
+            || self.encode_only_changed
+            || self.archive.is_some()
+            || self.csv.is_some()
+        {
             self.batch_conv(console, globals)
         } else {
             self.single_file_conv(console, globals)
@@ -82,50 +835,258 @@ impl EncodeFuncs for Avif {
         let mut console = console;
         console.set_spinner("Searching for files...");
 
-        let mut paths = parse_files(&self.path);
+        let mut paths = parse_files(&self.path, self.include_avif, globals.input_format());
+
+        let resume = match &self.resume {
+            Some(state) => Some(Arc::new(ResumeState::open(state)?)),
+            None => None,
+        };
+
+        if let Some(resume) = &resume {
+            let total = paths.len();
+            paths.retain(|item| !resume.is_done(item));
+            debug!(
+                "Resuming, {} of {total} files already done",
+                total - paths.len()
+            );
+        }
+
+        let changes = self
+            .encode_only_changed
+            .then(|| Arc::new(ChangeIndex::default()));
+
+        if let Some(changes) = &changes {
+            let total = paths.len();
+            paths.retain(|item| !changes.is_unchanged(item));
+            debug!(
+                "{} of {total} files unchanged since the last run",
+                total - paths.len()
+            );
+        }
+
         let psize = paths.len();
 
-        paths.sort_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+        if psize == 0 {
+            console.finish_spinner("Nothing to convert.");
+            return Ok(());
+        }
+
+        self.sort.apply(&mut paths);
+
+        let archive = match &self.archive {
+            Some(path) => Some(Arc::new(Archive::create(path)?)),
+            None => None,
+        };
 
         let con = console.finish_spinner(&format!("Found {psize} files."));
 
-        let job_num = calculate_tread_count(globals.threads, psize);
+        let mut job_num = calculate_tread_count(globals.threads, psize);
+        if let Some(max) = self.max_threads_per_image {
+            job_num.task_threads = job_num.task_threads.min(max as usize);
+        }
 
         let pool = ThreadPool::with_name("Encoder Thread".to_string(), job_num.spawn_threads);
 
-        let initial_size: u64 = paths.iter().map(|item| item.metadata.size).sum();
-
-        con.setup_bar(psize as u64);
+        if globals.progress_format == ProgressFormat::Bar {
+            con.setup_bar(psize as u64);
+        }
 
         let start = Instant::now();
 
+        #[cfg(feature = "ssim")]
+        let ssim_scores = Arc::new(Mutex::new(Vec::new()));
+
+        let archive_root = self.preserve_structure.then(|| common_root(&paths));
+
+        let csv_rows = self.csv.is_some().then(|| Arc::new(Mutex::new(Vec::new())));
+
+        let failures = Arc::new(Mutex::new(Vec::new()));
+
         for mut item in paths.drain(..) {
-            let globals = globals.clone();
+            if self.abort_on_first_error && FAILED_COUNT.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+
+            let globals = globals.with_dir_config(item.dir_config.as_ref());
+            let quality_set = self.quality_set.clone();
+            let widths = self.widths.clone();
+            let resume = resume.clone();
+            let changes = changes.clone();
+            let archive = archive.clone();
+            let root = archive_root.clone();
+            let csv_rows = csv_rows.clone();
+            let failures = failures.clone();
+            #[cfg(feature = "ssim")]
+            let ssim_scores = ssim_scores.clone();
             pool.execute(move || {
                 Globals::set_encoder_priority(globals.priority);
+                if globals.pin_threads {
+                    Globals::pin_encoder_thread();
+                }
                 let enc_start = Instant::now();
 
-                let bar = if globals.quiet {
+                let bar = if globals.verbosity() != Verbosity::Normal
+                    || globals.progress_format != ProgressFormat::Bar
+                {
                     None
                 } else {
                     Some(PROGRESS_BAR.clone())
                 };
+                let json_total =
+                    (globals.progress_format == ProgressFormat::Json).then_some(psize as u64);
+
+                // Files already running are left alone, only new ones are skipped. The slot
+                // is taken before encoding so parallel workers can't overshoot `--limit`
+                let limit = globals.limit.unwrap_or(u64::MAX);
+                if (self.abort_on_first_error && FAILED_COUNT.load(Ordering::SeqCst) > 0)
+                    || LIMIT_SLOTS
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |taken| {
+                            (taken < limit).then_some(taken + 1)
+                        })
+                        .is_err()
+                {
+                    if let Some(bar) = bar {
+                        bar.inc(1);
+                    }
+                    ITEMS_PROCESSED.fetch_add(1, Ordering::SeqCst);
+                    return;
+                }
+
+                let options = globals.encode_options(job_num.task_threads);
+
+                if let Err(err) = item.prepare(&options) {
+                    skip_failed(&item, &err, bar, json_total, &failures);
+                    return;
+                }
 
-                if let Ok(r_size) = item.convert_to_avif_stored(
-                    globals.quality,
-                    globals.speed,
-                    job_num.task_threads,
-                    globals.bit_depth,
-                    globals.remove_alpha,
-                    bar,
-                ) {
-                    SUCCESS_COUNT.fetch_add(1, Ordering::SeqCst);
-                    FINAL_STATS.fetch_add(r_size, Ordering::SeqCst);
+                for note in [globals.profile_note(&item), globals.bit_depth_note(&item)]
+                    .into_iter()
+                    .flatten()
+                {
+                    ConsoleMsg::new(globals.verbosity(), false)
+                        .print_message(note.yellow().to_string());
                 }
 
-                if !self.benchmark {
-                    item.save_avif(None, globals.name_type, globals.keep)
-                        .unwrap();
+                let variants = variants(&quality_set, &widths, options, item.width);
+
+                if variants.is_empty() {
+                    let err = eyre!("narrower than every --widths entry");
+                    skip_failed(&item, &err, bar, json_total, &failures);
+                    return;
+                }
+
+                let last = variants.len() - 1;
+                let mut rows = Vec::new();
+
+                for (i, variant) in variants.into_iter().enumerate() {
+                    item.suffix = variant.suffix;
+
+                    // The bar counts sources, not outputs
+                    let progress = if i == last { bar.clone() } else { None };
+
+                    match item.convert_to_avif_stored(&variant.options, progress) {
+                        Ok(r_size) => {
+                            if let Some(note) = globals.alpha_note(&item) {
+                                ConsoleMsg::new(globals.verbosity(), false)
+                                    .print_message(note.yellow().to_string());
+                            }
+
+                            // Every output goes through the SSIM gate, the lowest quality
+                            // one is the most likely to fail it
+                            #[cfg(feature = "ssim")]
+                            let ssim = self
+                                .ssim
+                                .then(|| {
+                                    record_ssim(
+                                        &item,
+                                        self.ssim_scale,
+                                        self.ssim_save,
+                                        &ssim_scores,
+                                    )
+                                })
+                                .flatten();
+                            #[cfg(not(feature = "ssim"))]
+                            let ssim = None;
+
+                            if csv_rows.is_some() {
+                                let (width, height) = item.encoded_dimensions;
+                                rows.push(CsvRow {
+                                    file: item.original_name(),
+                                    width,
+                                    height,
+                                    original_bytes: item.metadata.size,
+                                    encoded_bytes: r_size,
+                                    quality: variant.options.quality,
+                                    ssim,
+                                });
+                            }
+
+                            SUCCESS_COUNT.fetch_add(1, Ordering::SeqCst);
+                            FINAL_STATS.fetch_add(r_size, Ordering::SeqCst);
+                            SOURCE_STATS.fetch_add(item.metadata.size, Ordering::SeqCst);
+
+                            if r_size > item.metadata.size {
+                                debug!("{} grew after conversion", item.original_name());
+                                GROWN_COUNT.fetch_add(1, Ordering::SeqCst);
+                                GROWN_STATS
+                                    .fetch_add(r_size - item.metadata.size, Ordering::SeqCst);
+                            }
+                        }
+                        Err(err) => {
+                            skip_failed(&item, &err, bar, json_total, &failures);
+                            return;
+                        }
+                    }
+
+                    if let Some(archive) = &archive {
+                        let options = globals.save_options();
+                        let name = archive_entry_name(&item, &options, root.as_deref());
+
+                        match archive.append(&name, &item.encoded_data) {
+                            Ok(true) => {}
+                            // Same hash, same content
+                            Ok(false) if options.name.is_content_hash() => {
+                                DEDUP_COUNT.fetch_add(1, Ordering::SeqCst);
+                                DEDUP_STATS
+                                    .fetch_add(item.encoded_data.len() as u64, Ordering::SeqCst);
+                            }
+                            Ok(false) => {
+                                let err = eyre!("{name} is already in the archive");
+                                skip_failed(&item, &err, bar, json_total, &failures);
+                                return;
+                            }
+                            Err(err) => {
+                                skip_failed(&item, &err, bar, json_total, &failures);
+                                return;
+                            }
+                        }
+                    } else if !self.benchmark {
+                        let saved = match item.save_avif(None, &globals.save_options()) {
+                            Ok(saved) => saved,
+                            Err(err) => {
+                                skip_failed(&item, &err, bar, json_total, &failures);
+                                return;
+                            }
+                        };
+
+                        if saved.duplicate {
+                            DEDUP_COUNT.fetch_add(1, Ordering::SeqCst);
+                            DEDUP_STATS.fetch_add(item.encoded_data.len() as u64, Ordering::SeqCst);
+                        }
+
+                        if let Some(command) = &globals.exec {
+                            let ratio = item.encoded_data.len() as f64 / item.metadata.size as f64;
+                            run_exec_hook(command, &item.metadata.path, &saved.path, ratio);
+                        }
+                    }
+                }
+
+                if let Some(resume) = &resume {
+                    resume.mark_done(&item);
+                }
+
+                if let Some(changes) = &changes {
+                    changes.mark_converted(&item);
                 }
 
                 trace!(
@@ -135,9 +1096,26 @@ impl EncodeFuncs for Avif {
                     start.elapsed().bold().green()
                 );
 
-                drop(item);
+                if let Some(csv_rows) = &csv_rows {
+                    csv_rows.lock().unwrap().extend(rows);
+                }
+
+                let done = ITEMS_PROCESSED.fetch_add(1, Ordering::SeqCst) + 1;
 
-                ITEMS_PROCESSED.fetch_add(1, Ordering::SeqCst);
+                if globals.progress_format == ProgressFormat::Json {
+                    ProgressEvent {
+                        done,
+                        total: psize as u64,
+                        file: &item.metadata.filename,
+                        color_bytes: item.color_byte_size,
+                        alpha_bytes: item.alpha_byte_size,
+                        timings: item.timings,
+                        error: None,
+                    }
+                    .emit();
+                }
+
+                drop(item);
 
                 if globals.quiet {
                     debug!(
@@ -150,6 +1128,28 @@ impl EncodeFuncs for Avif {
 
         pool.join();
 
+        if let Some(archive) = &archive {
+            archive.finish()?;
+        }
+
+        if let (Some(path), Some(csv_rows)) = (&self.csv, &csv_rows) {
+            let mut rows = csv_rows.lock().unwrap();
+            rows.sort_by(|a, b| a.file.cmp(&b.file));
+            CsvRow::write_all(path, &rows)?;
+        }
+
+        // The archive and report above still cover what got done
+        let failed = FAILED_COUNT.load(Ordering::SeqCst);
+
+        let mut failures = std::mem::take(&mut *failures.lock().unwrap());
+        failures.sort();
+
+        if self.abort_on_first_error && failed > 0 {
+            con.finish_bar();
+            print_failures(&con, &failures);
+            bail!("Stopped after {failed} failed files")
+        }
+
         let elapsed = start.elapsed();
 
         con.finish_bar();
@@ -159,10 +1159,16 @@ impl EncodeFuncs for Avif {
             *"New folder size".bold().0,
         ];
 
+        let initial_size = SOURCE_STATS.load(Ordering::SeqCst);
+
         debug!("Final stats: {}", FINAL_STATS.load(Ordering::Relaxed));
         debug!("Initial size: {}", initial_size);
 
-        let initial_delta = FINAL_STATS.load(Ordering::Relaxed) as f32 / initial_size as f32;
+        // Nothing was converted when no source bytes were counted
+        let initial_delta = match initial_size {
+            0 => 1.,
+            _ => FINAL_STATS.load(Ordering::Relaxed) as f32 / initial_size as f32,
+        };
 
         let delta = (initial_delta * 100.) - 100.;
 
@@ -188,19 +1194,112 @@ impl EncodeFuncs for Avif {
             }
         };
 
-        con.print_message(format!(
-            "Encoded {} files in {elapsed:.2?}.\n{} {} | {} {} ({} or {})",
-            SUCCESS_COUNT.load(Ordering::SeqCst),
-            texts[0],
-            ByteSize::b(initial_size).to_string_as(true).blue().bold(),
-            texts[1],
-            ByteSize::b(FINAL_STATS.load(Ordering::SeqCst))
-                .to_string_as(true)
-                .green()
-                .bold(),
-            percentage,
-            times
-        ));
+        let duplicates = DEDUP_COUNT.load(Ordering::SeqCst);
+        let grown = GROWN_COUNT.load(Ordering::SeqCst);
+        let timed_out = TIMED_OUT_COUNT.load(Ordering::SeqCst);
+
+        #[cfg(feature = "ssim")]
+        let (average_ssim, below_ssim) = {
+            let ssim_scores = ssim_scores.lock().unwrap();
+            let average = (!ssim_scores.is_empty()).then(|| {
+                ssim_scores.iter().map(|(_, score)| score).sum::<f64>() / ssim_scores.len() as f64
+            });
+
+            let mut below: Vec<(String, f64)> = match self.ssim_threshold {
+                Some(threshold) => ssim_scores
+                    .iter()
+                    .filter(|(_, score)| *score < threshold)
+                    .cloned()
+                    .collect(),
+                None => Vec::new(),
+            };
+            below.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+            (average, below)
+        };
+        #[cfg(not(feature = "ssim"))]
+        let average_ssim = None;
+
+        if self.stdout_summary == Some(SummaryFormat::Json) {
+            BatchSummary {
+                converted: SUCCESS_COUNT.load(Ordering::SeqCst),
+                failed: FAILED_COUNT.load(Ordering::SeqCst),
+                duplicates,
+                grown,
+                timed_out,
+                original_size: initial_size,
+                new_size: FINAL_STATS.load(Ordering::SeqCst),
+                ratio: initial_delta as f64,
+                elapsed_secs: elapsed.as_secs_f64(),
+                average_ssim,
+                failures,
+            }
+            .print();
+        } else {
+            con.print_summary(format!(
+                "Encoded {} files in {elapsed:.2?}.\n{} {} | {} {} ({} or {})",
+                SUCCESS_COUNT.load(Ordering::SeqCst),
+                texts[0],
+                ByteSize::b(initial_size).to_string_as(true).blue().bold(),
+                texts[1],
+                ByteSize::b(FINAL_STATS.load(Ordering::SeqCst))
+                    .to_string_as(true)
+                    .green()
+                    .bold(),
+                percentage,
+                times
+            ));
+
+            if duplicates > 0 {
+                con.print_summary(format!(
+                    "Skipped {} duplicate outputs ({} saved)",
+                    duplicates.bold(),
+                    ByteSize::b(DEDUP_STATS.load(Ordering::SeqCst))
+                        .to_string_as(true)
+                        .green()
+                        .bold()
+                ));
+            }
+
+            if grown > 0 {
+                con.print_summary(format!(
+                    "{} files got bigger after conversion (+{})",
+                    grown.red().bold(),
+                    ByteSize::b(GROWN_STATS.load(Ordering::SeqCst))
+                        .to_string_as(true)
+                        .red()
+                        .bold()
+                ));
+            }
+
+            if let Some(average) = average_ssim {
+                con.print_summary(format!(
+                    "Average SSIM: {}",
+                    format!("{average:.4}").bold().green()
+                ));
+            }
+
+            if timed_out > 0 {
+                con.print_summary(format!(
+                    "{} files timed out and were skipped",
+                    timed_out.red().bold()
+                ));
+            }
+
+            print_failures(&con, &failures);
+
+            #[cfg(feature = "ssim")]
+            if !below_ssim.is_empty() {
+                con.print_summary(format!(
+                    "{} files are below the SSIM threshold:",
+                    below_ssim.len().red().bold()
+                ));
+
+                for (name, score) in &below_ssim {
+                    con.print_summary(format!("  {name}: {}", format!("{score:.4}").red()));
+                }
+            }
+        }
 
         con.notify_text(&format!(
             "Encoded {} files in {elapsed:.2?}\n{} → {}",
@@ -209,14 +1308,40 @@ impl EncodeFuncs for Avif {
             ByteSize::b(FINAL_STATS.load(Ordering::SeqCst)).to_string_as(true)
         ))?;
 
+        // Every reason to exit non-zero is reported, not just the first one
+        let mut problems = Vec::new();
+
+        if failed > 0 {
+            problems.push(format!("{failed} files failed to convert"));
+        }
+
+        if self.fail_on_growth && grown > 0 {
+            problems.push(format!("{grown} files got bigger after conversion"));
+        }
+
+        #[cfg(feature = "ssim")]
+        if self.fail_below_ssim && !below_ssim.is_empty() {
+            problems.push(format!(
+                "{} files are below the SSIM threshold",
+                below_ssim.len()
+            ));
+        }
+
+        if !problems.is_empty() {
+            bail!("{}", problems.join(", "))
+        }
+
         Ok(())
     }
 
     fn single_file_conv(self, console: ConsoleMsg, globals: &Globals) -> Result<()> {
         let mut console = console;
-        let mut image = ImageFile::new_from_path(&self.path[0])?;
+        let mut image = ImageFile::open(&self.path[0], globals.input_format())?;
         let image_size = image.metadata.size;
 
+        image.dir_config = DirConfig::for_file(&self.path[0]);
+        let globals = &globals.with_dir_config(image.dir_config.as_ref());
+
         console.print_message(format!(
             "Encoding single file {} ({})",
             image.metadata.name.bold(),
@@ -230,19 +1355,112 @@ impl EncodeFuncs for Avif {
 
         let start = Instant::now();
 
-        let fsz = image.convert_to_avif_stored(
-            globals.quality,
-            globals.speed,
-            sys_threads(globals.threads),
-            globals.bit_depth,
-            globals.remove_alpha,
-            None,
-        )?;
+        let mut fsz = 0;
+        let mut grew = false;
+
+        let options = globals.encode_options(sys_threads(globals.threads));
+        image.prepare(&options)?;
+
+        for note in [globals.profile_note(&image), globals.bit_depth_note(&image)]
+            .into_iter()
+            .flatten()
+        {
+            console.print_message(note.yellow().to_string());
+        }
+
+        let variants = variants(&self.quality_set, &self.widths, options, image.width);
+
+        if variants.is_empty() {
+            bail!(
+                "{} is narrower than every --widths entry",
+                image.original_name()
+            )
+        }
+
+        for variant in variants {
+            image.suffix = variant.suffix;
+
+            let size = image.convert_to_avif_stored(&variant.options, None)?;
+            fsz += size;
+
+            if size > image_size {
+                grew = true;
+                console.print_message(format!(
+                    "{} got bigger after conversion",
+                    image.original_name().red()
+                ));
+            }
+
+            if self.benchmark {
+                continue;
+            }
 
-        if !self.benchmark {
-            image.save_avif(self.output_file, globals.name_type, globals.keep)?;
+            let saved = image.save_avif(self.output_file.clone(), &globals.save_options())?;
+
+            if saved.duplicate {
+                console.print_message(format!(
+                    "Identical to {}, nothing written",
+                    saved.path.display().bold()
+                ));
+            }
+
+            if let Some(command) = &globals.exec {
+                run_exec_hook(
+                    command,
+                    &image.metadata.path,
+                    &saved.path,
+                    size as f64 / image_size as f64,
+                );
+            }
+        }
+
+        if let Some(note) = globals.alpha_note(&image) {
+            console.print_message(note.yellow().to_string());
+        }
+
+        if image.alpha_byte_size > 0 {
+            console.print_message(format!(
+                "AV1 payload: {} color, {} alpha",
+                ByteSize::b(image.color_byte_size as u64).to_string_as(true),
+                ByteSize::b(image.alpha_byte_size as u64).to_string_as(true)
+            ));
+        }
+
+        if self.fail_on_growth && grew {
+            bail!("{} got bigger after conversion", image.original_name())
+        }
+
+        if globals.progress_format == ProgressFormat::Json {
+            ProgressEvent {
+                done: 1,
+                total: 1,
+                file: &image.metadata.filename,
+                color_bytes: image.color_byte_size,
+                alpha_bytes: image.alpha_byte_size,
+                timings: image.timings,
+                error: None,
+            }
+            .emit();
         }
 
+        let elapsed = start.elapsed();
+
+        let preview = if self.preview {
+            let path = image.preview_path();
+            image.quality_preview(PREVIEW_SIZE)?.save(&path)?;
+            Some(path)
+        } else {
+            None
+        };
+
+        #[cfg(feature = "ssim")]
+        let ssim = if self.ssim {
+            let (score, diff) = image.measure_ssim(self.ssim_scale)?;
+            Some((score, diff, image.ssim_diff_path()))
+        } else {
+            None
+        };
+
         let bmp = image.bitmap.clone();
 
         drop(image);
@@ -250,19 +1468,99 @@ impl EncodeFuncs for Avif {
         console.notify_image(
             &format!(
                 "Finished in {:.2?} \n {} → {}",
-                start.elapsed(),
+                elapsed,
                 ByteSize::b(image_size).to_string_as(true),
                 ByteSize::b(fsz).to_string_as(true)
             ),
             bmp,
         )?;
 
-        console.finish_spinner(&format!(
+        let console = console.finish_summary(&format!(
             "Encoding finished in {:?} ({})",
-            start.elapsed(),
+            elapsed,
             ByteSize::b(fsz).to_string_as(true).bold().green()
         ));
 
+        if let Some(path) = preview {
+            console.print_message(format!("Saved preview to {}", path.display().bold()));
+        }
+
+        #[cfg(feature = "ssim")]
+        if let Some((score, diff, diff_path)) = ssim {
+            let below = self
+                .ssim_threshold
+                .is_some_and(|threshold| score < threshold);
+
+            if below {
+                console.print_summary(format!("SSIM: {}", format!("{score:.4}").bold().red()));
+            } else {
+                console.print_summary(format!("SSIM: {}", format!("{score:.4}").bold().green()));
+            }
+
+            if self.ssim_save {
+                apply_colormap(&diff).save(&diff_path)?;
+                console.print_message(format!(
+                    "Saved SSIM difference to {}",
+                    diff_path.display().bold()
+                ));
+            }
+
+            if below && self.fail_below_ssim {
+                bail!(
+                    "{} is below the SSIM threshold ({score:.4})",
+                    self.path[0].display()
+                )
+            }
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use clap::Parser;
+    use image::RgbImage;
+
+    use super::*;
+    use crate::cli::commands::Commands;
+
+    #[test]
+    fn corrupt_file_doesnt_stop_the_batch() {
+        let dir = env::temp_dir().join(format!("avif-converter-{}-batch", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let good = ["a.png", "c.png"].map(|name| dir.join(name));
+        for path in &good {
+            RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 90]))
+                .save(path)
+                .unwrap();
+        }
+
+        // Cut off after the header, the dimensions are still readable
+        let bad = dir.join("b.png");
+        fs::write(&bad, &fs::read(&good[0]).unwrap()[..100]).unwrap();
+
+        let args = [&good[0], &bad, &good[1]].map(|path| path.to_str().unwrap());
+        let globals = Globals::try_parse_from(
+            ["avif-converter", "-s", "10", "-n", "same", "-k", "avif"]
+                .into_iter()
+                .chain(args),
+        )
+        .unwrap();
+        let Commands::Avif(avif) = globals.command.clone() else {
+            unreachable!()
+        };
+
+        let result = avif.batch_conv(ConsoleMsg::new(Verbosity::Quiet, false), &globals);
+
+        assert!(result.is_err());
+        assert!(dir.join("a.avif").is_file());
+        assert!(dir.join("c.avif").is_file());
+        assert!(!dir.join("b.avif").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
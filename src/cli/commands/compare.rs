@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use color_eyre::eyre::bail;
+use image::GenericImageView;
+use owo_colors::OwoColorize;
+
+use crate::{
+    cli::Args as Globals,
+    console::ConsoleMsg,
+    decoders::avif::get_avif_bitmap,
+    ssim::{apply_colormap, calculate_ssim_and_diff, overlay_images},
+};
+use color_eyre::Result;
+
+#[derive(Args, Debug, Clone)]
+#[clap(author, about, long_about = None)]
+pub struct Compare {
+    /// Original image used as reference
+    #[clap(value_name = "ORIGINAL")]
+    pub original: PathBuf,
+
+    /// AVIF file to compare against the original
+    #[clap(value_name = "AVIF")]
+    pub encoded: PathBuf,
+
+    /// Only write the SSIM heatmap overlay instead of printing the score
+    #[clap(long, default_value_t = false)]
+    pub overlay_only: bool,
+
+    /// Heatmap output path. Defaults to `<ORIGINAL>_ssim.png`
+    #[clap(short, long, requires = "overlay_only", value_name = "OUTPUT")]
+    pub output_file: Option<PathBuf>,
+}
+
+impl Compare {
+    pub fn run(self, globals: &Globals) -> Result<()> {
+        let console = ConsoleMsg::new(globals.verbosity(), false);
+
+        let original = image::open(&self.original)?;
+        let encoded = get_avif_bitmap(&self.encoded)?;
+
+        if original.dimensions() != encoded.dimensions() {
+            bail!(
+                "Image dimensions differ: {:?} vs {:?}",
+                original.dimensions(),
+                encoded.dimensions()
+            )
+        }
+
+        let (ssim, diff) = calculate_ssim_and_diff(&original.to_luma8(), &encoded.to_luma8());
+
+        if !self.overlay_only {
+            console.print_summary(format!("SSIM: {}", format!("{ssim:.4}").bold().green()));
+            return Ok(());
+        }
+
+        let output = self.output_file.unwrap_or_else(|| {
+            let stem = self.original.file_stem().unwrap().to_string_lossy();
+            self.original.with_file_name(format!("{stem}_ssim.png"))
+        });
+
+        overlay_images(&original, &apply_colormap(&diff), 0.5).save(&output)?;
+
+        console.print_message(format!("Saved SSIM heatmap to {}", output.display().bold()));
+
+        Ok(())
+    }
+}
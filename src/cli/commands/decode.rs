@@ -0,0 +1,229 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{Cursor, ErrorKind, Seek, Write},
+    path::{Path, PathBuf},
+    process::exit,
+    time::Instant,
+};
+
+use bytesize::ByteSize;
+use clap::{Args, ValueEnum};
+use color_eyre::eyre::bail;
+use image::{DynamicImage, ImageFormat};
+use log::error;
+use owo_colors::OwoColorize;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    cli::Args as Globals,
+    console::ConsoleMsg,
+    decoders::avif::get_avif_bitmap,
+    image_file::ImageFile,
+    utils::{is_avif, parse_files, retry_io},
+};
+use color_eyre::Result;
+
+#[derive(Args, Debug, Clone)]
+#[clap(author, about, long_about = None)]
+pub struct Decode {
+    /// AVIF file or directory containing AVIF files to decode
+    #[clap(value_name = "PATH", required = true)]
+    pub path: Vec<PathBuf>,
+
+    /// Format to write next to each AVIF
+    #[clap(long, value_enum, default_value_t = DecodeFormat::Png)]
+    pub to: DecodeFormat,
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum, PartialEq, Eq)]
+pub enum DecodeFormat {
+    /// Lossless, keeps 10 and 12-bit images at 16 bits
+    Png,
+    /// 8-bit, transparency is dropped
+    Jpeg,
+    /// Lossless 8-bit
+    Webp,
+    /// Uncompressed 8-bit, keeps transparency
+    Bmp,
+}
+
+impl DecodeFormat {
+    fn image_format(self) -> ImageFormat {
+        match self {
+            DecodeFormat::Png => ImageFormat::Png,
+            DecodeFormat::Jpeg => ImageFormat::Jpeg,
+            DecodeFormat::Webp => ImageFormat::WebP,
+            DecodeFormat::Bmp => ImageFormat::Bmp,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            DecodeFormat::Png => "png",
+            DecodeFormat::Jpeg => "jpg",
+            DecodeFormat::Webp => "webp",
+            DecodeFormat::Bmp => "bmp",
+        }
+    }
+
+    /// Only PNG takes 16-bit pixels and JPEG has no alpha channel
+    fn prepare(self, image: DynamicImage) -> DynamicImage {
+        let color = image.color();
+
+        match self {
+            DecodeFormat::Png => image,
+            DecodeFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+            DecodeFormat::Webp | DecodeFormat::Bmp if color.has_alpha() => {
+                DynamicImage::ImageRgba8(image.to_rgba8())
+            }
+            DecodeFormat::Webp | DecodeFormat::Bmp => DynamicImage::ImageRgb8(image.to_rgb8()),
+        }
+    }
+}
+
+impl Decode {
+    /// Decode every AVIF in parallel and save it as `--to`. A file that fails is reported
+    /// and skipped, the others are still written
+    pub fn run(self, globals: &Globals) -> Result<()> {
+        let mut console = ConsoleMsg::new(globals.verbosity(), false);
+        console.set_spinner("Searching for files...");
+
+        let files: Vec<ImageFile> = parse_files(&self.path, true, None)
+            .into_iter()
+            .filter(|image| is_avif(&image.metadata.path))
+            .collect();
+        let psize = files.len();
+
+        if psize == 0 {
+            bail!("No AVIF files found");
+        }
+
+        console.set_spinner(format!("Decoding {psize} files..."));
+
+        let start = Instant::now();
+
+        let mut results: Vec<(String, u64, Result<u64, String>)> = files
+            .into_par_iter()
+            .map(|mut image| {
+                let name = image.original_name();
+                let size = image.metadata.size;
+                let saved = self
+                    .decode_file(&mut image, globals)
+                    .map_err(|err| err.to_string());
+
+                (name, size, saved)
+            })
+            .collect();
+
+        let elapsed = start.elapsed();
+
+        results.sort();
+
+        let console = console.finish_spinner(&format!("Decoded {psize} files."));
+
+        let mut failed = 0;
+        for (name, reason) in results
+            .iter()
+            .filter_map(|(name, _, result)| result.as_ref().err().map(|reason| (name, reason)))
+        {
+            console.print_summary(format!("{} {name}: {reason}", "✗".red().bold()));
+            failed += 1;
+        }
+
+        // Failed files wrote nothing, so they don't count on either side
+        let (initial_size, new_size) = results
+            .iter()
+            .filter_map(|(_, size, result)| Some((size, result.as_ref().ok()?)))
+            .fold((0, 0), |(initial, new), (size, saved)| {
+                (initial + size, new + saved)
+            });
+        let delta = match initial_size {
+            0 => 0.,
+            _ => (new_size as f32 / initial_size as f32 * 100.) - 100.,
+        };
+
+        let percentage = if delta < 0. {
+            format!("{delta:.2}%").green().to_string()
+        } else {
+            format!("+{delta:.2}%").red().to_string()
+        };
+
+        console.print_summary(format!(
+            "Decoded {} files in {elapsed:.2?}.\n{} {} | {} {} ({percentage})",
+            psize - failed,
+            "Original folder size".bold(),
+            ByteSize::b(initial_size).to_string_as(true).blue().bold(),
+            "New folder size".bold(),
+            ByteSize::b(new_size).to_string_as(true).green().bold(),
+        ));
+
+        if failed > 0 {
+            exit(1);
+        }
+
+        Ok(())
+    }
+
+    /// Write one decoded file, returns its size
+    fn decode_file(&self, image: &mut ImageFile, globals: &Globals) -> Result<u64> {
+        let path = image.metadata.path.clone();
+        let bitmap = retry_io(globals.io_retries, &path, || get_avif_bitmap(&path))?;
+
+        let mut data = Vec::new();
+        self.to
+            .prepare(bitmap)
+            .write_to(&mut Cursor::new(&mut data), self.to.image_format())?;
+
+        // Hash names are taken from the written bytes, like the AVIF outputs
+        image.encoded_data = data;
+        let name = globals
+            .name_type
+            .generate_name(image, globals.suffix.as_deref());
+
+        let target = output_dir(&path).join(format!("{name}.{}", self.to.extension()));
+
+        // Never overwrite, the target may be the original the AVIF was made from.
+        // Creating it up front also keeps two workers from picking the same name
+        let mut file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&target)
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                bail!("{} already exists", target.display())
+            }
+            Err(err) => Err(err)?,
+        };
+
+        let written = retry_io(globals.io_retries, &target, || {
+            // A retry starts over, the failed attempt may have written part of the data
+            file.set_len(0)?;
+            file.rewind()?;
+            file.write_all(&image.encoded_data)?;
+            Ok(())
+        });
+
+        // Don't leave a partial file behind, reruns would refuse to replace it
+        if let Err(err) = written {
+            drop(file);
+            if let Err(remove) = fs::remove_file(&target) {
+                error!("Failed to remove {}: {remove}", target.display());
+            }
+            return Err(err);
+        }
+
+        if !globals.keep {
+            fs::remove_file(&path)?;
+        }
+
+        Ok(image.encoded_data.len() as u64)
+    }
+}
+
+fn output_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
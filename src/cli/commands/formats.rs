@@ -0,0 +1,42 @@
+use clap::Args;
+use owo_colors::OwoColorize;
+
+use crate::{cli::Args as Globals, console::ConsoleMsg, image_file::SUPPORTED_EXTENSIONS};
+use color_eyre::Result;
+
+#[derive(Args, Debug, Clone)]
+#[clap(author, about, long_about = None)]
+pub struct ListFormats {}
+
+impl ListFormats {
+    pub fn run(self, globals: &Globals) -> Result<()> {
+        let console = ConsoleMsg::new(globals.verbosity(), false);
+
+        #[allow(unused_mut)]
+        let mut extensions = SUPPORTED_EXTENSIONS.to_vec();
+        #[cfg(feature = "raw")]
+        extensions.extend_from_slice(crate::decoders::raw::RAW_EXTENSIONS);
+
+        let features: Vec<&str> = [
+            ("ssim", cfg!(feature = "ssim")),
+            ("raw", cfg!(feature = "raw")),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect();
+
+        console.print_summary(format!(
+            "{} {}\n{} {}",
+            "Input formats:".bold(),
+            extensions.join(", "),
+            "Enabled features:".bold(),
+            if features.is_empty() {
+                "none".to_string()
+            } else {
+                features.join(", ")
+            }
+        ));
+
+        Ok(())
+    }
+}
@@ -2,13 +2,18 @@ use clap::Subcommand;
 
 use crate::console::ConsoleMsg;
 
-use self::{avif::Avif, watch::Watch};
+use self::{avif::Avif, decode::Decode, formats::ListFormats, verify::Verify, watch::Watch};
 
 use super::Args as Globals;
 use color_eyre::Result;
 
 pub mod avif;
+#[cfg(feature = "ssim")]
+pub mod compare;
+pub mod decode;
+pub mod formats;
 //pub mod png;
+pub mod verify;
 pub mod watch;
 
 #[derive(Debug, Subcommand, Clone)]
@@ -17,6 +22,15 @@ pub enum Commands {
     Avif(Avif),
     /// Watch directory for new image files and convert them
     Watch(Watch),
+    /// Compare an AVIF against its original image
+    #[cfg(feature = "ssim")]
+    Compare(compare::Compare),
+    /// List accepted input formats and enabled features
+    ListFormats(ListFormats),
+    /// Decode AVIF files back to PNG, JPEG, WebP or BMP
+    Decode(Decode),
+    /// Check that AVIF files still decode and print their dimensions, depth and alpha
+    Verify(Verify),
 }
 
 pub trait EncodeFuncs {
@@ -0,0 +1,104 @@
+use std::{path::PathBuf, process::exit};
+
+use clap::Args;
+use image::DynamicImage;
+use owo_colors::OwoColorize;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    cli::Args as Globals,
+    console::ConsoleMsg,
+    decoders::avif::get_avif_bitmap,
+    image_file::effective_bit_depth,
+    utils::{is_avif, parse_files, retry_io},
+};
+use color_eyre::Result;
+
+#[derive(Args, Debug, Clone)]
+#[clap(author, about, long_about = None)]
+pub struct Verify {
+    /// AVIF files or directories containing them
+    #[clap(value_name = "PATH", required = true)]
+    pub path: Vec<PathBuf>,
+}
+
+impl Verify {
+    /// Fully decode every AVIF in parallel, print what each one holds and list the ones
+    /// that don't decode
+    pub fn run(self, globals: &Globals) -> Result<()> {
+        let mut console = ConsoleMsg::new(globals.verbosity(), false);
+        console.set_spinner("Searching for files...");
+
+        let files: Vec<PathBuf> = parse_files(&self.path, true, None)
+            .into_iter()
+            .map(|image| image.metadata.path)
+            .filter(|path| is_avif(path))
+            .collect();
+        let psize = files.len();
+
+        console.set_spinner(format!("Decoding {psize} files..."));
+
+        let mut results: Vec<(String, Result<String, String>)> = files
+            .into_par_iter()
+            .map(|path| {
+                let decoded = retry_io(globals.io_retries, &path, || get_avif_bitmap(&path));
+                let name = path.display().to_string();
+
+                (
+                    name,
+                    decoded
+                        .map(|image| describe(&image))
+                        .map_err(|err| err.to_string()),
+                )
+            })
+            .collect();
+
+        results.sort();
+
+        let console = console.finish_spinner(&format!("Decoded {psize} files."));
+
+        for (name, result) in &results {
+            match result {
+                Ok(properties) => {
+                    console.print_message(format!("{} {name}: {properties}", "✓".green().bold()))
+                }
+                Err(reason) => {
+                    console.print_summary(format!("{} {name}: {reason}", "✗".red().bold()))
+                }
+            }
+        }
+
+        let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+
+        console.print_summary(format!(
+            "{} passed | {} failed",
+            (psize - failed).green().bold(),
+            failed.red().bold()
+        ));
+
+        if failed > 0 {
+            exit(1);
+        }
+
+        Ok(())
+    }
+}
+
+/// Dimensions, bit depth and transparency of a decoded AVIF. The decoder always hands
+/// out RGBA at 8 or 16 bits, so the depth is read back from the samples and alpha only
+/// counts when some pixel isn't opaque
+fn describe(image: &DynamicImage) -> String {
+    let alpha = match image {
+        DynamicImage::ImageRgba8(buffer) => buffer.pixels().any(|px| px[3] != u8::MAX),
+        DynamicImage::ImageRgba16(buffer) => buffer.pixels().any(|px| px[3] != u16::MAX),
+        _ => image.color().has_alpha(),
+    };
+
+    format!(
+        "{}x{}, {}-bit{}",
+        image.width(),
+        image.height(),
+        effective_bit_depth(image),
+        if alpha { ", alpha" } else { "" }
+    )
+}
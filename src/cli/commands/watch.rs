@@ -1,17 +1,26 @@
 use bytesize::ByteSize;
 use color_eyre::Result;
-use log::{error, info};
-use notify::{event::CreateKind, Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use log::{debug, error, info};
+use notify::{
+    event::CreateKind, Config, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+};
 use std::{
+    collections::HashSet,
     path::{Path, PathBuf},
-    time::Instant,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
 };
 use threadpool::ThreadPool;
 
 use crate::{
     cli::Args as Globals,
+    config::DirConfig,
+    console::ConsoleMsg,
     image_file::ImageFile,
-    utils::{sys_threads, truncate_str},
+    utils::{calculate_tread_count, is_avif, run_exec_hook, sys_threads, truncate_str},
 };
 use clap::Args;
 
@@ -21,6 +30,26 @@ pub struct Watch {
     /// File or directory to watch
     #[clap(value_name = "PATH")]
     pub path: PathBuf,
+
+    /// Maximum number of files queued or encoding, running encodes included. New events
+    /// wait until one of them is done
+    #[clap(long, default_value_t = 128, value_name = "COUNT")]
+    pub max_queue: usize,
+
+    /// Poll the directory every SECONDS instead of relying on filesystem events.
+    /// Needed for NFS/SMB mounts, where inotify and FSEvents don't fire reliably
+    #[clap(long, value_name = "SECONDS")]
+    pub poll: Option<u64>,
+
+    /// Collect new files for MS milliseconds after the first one, then convert them together.
+    /// Threads are split between the files of a burst like the batch command does,
+    /// instead of encoding each file on a single thread
+    #[clap(long, value_name = "MS")]
+    pub batch_window: Option<u64>,
+
+    /// Send a notification to the desktop for every converted file
+    #[clap(short = 'N', long, default_value_t = false)]
+    pub notify: bool,
 }
 
 impl Watch {
@@ -37,63 +66,271 @@ impl Watch {
         let pool =
             ThreadPool::with_name("Encoder Thread".to_string(), sys_threads(globals.threads));
 
-        let (tx, rx) = std::sync::mpsc::channel();
+        let max_queue = self.max_queue.max(1);
+        let slots = Arc::new(QueueSlots::default());
+
+        // Bounded too, so events don't pile up while dispatch waits for a free slot
+        let (tx, rx) = mpsc::sync_channel(max_queue);
+        let handler = move |event| {
+            let _ = tx.send(event);
+        };
 
         // Create a new debounced file watcher with a timeout of 2 seconds.
         // The tickrate will be selected automatically, as well as the underlying watch implementation.
-        let mut watcher = RecommendedWatcher::new(tx, Config::default())?;
+        let mut watcher: Box<dyn Watcher> = if let Some(interval) = self.poll {
+            info!("Polling every {interval}s");
+            let config = Config::default().with_poll_interval(Duration::from_secs(interval.max(1)));
+            Box::new(PollWatcher::new(handler, config)?)
+        } else {
+            Box::new(RecommendedWatcher::new(handler, Config::default())?)
+        };
 
         // Add a path to be watched. All files and directories at that path and
         // below will be monitored for changes.
         watcher.watch(&self.path, RecursiveMode::Recursive)?;
 
-        // print all events and errors
-        for result in rx {
+        let window = self.batch_window.map(Duration::from_millis);
+        // A file usually fires several events while it's being written, each is converted once
+        let mut pending: HashSet<PathBuf> = HashSet::new();
+        let mut flush_at: Option<Instant> = None;
+        let mut dispatched = 0;
+
+        loop {
+            let result = match flush_at {
+                Some(deadline) => {
+                    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(result) => Some(result),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match rx.recv() {
+                    Ok(result) => Some(result),
+                    Err(_) => break,
+                },
+            };
+
+            let window_over = result.is_none();
+
             match result {
-                Ok(events) => {
-                    if events.kind == EventKind::Create(CreateKind::File) {
+                Some(Ok(events)) => {
+                    // The poll watcher can't tell files from directories and reports `Any`
+                    if matches!(
+                        events.kind,
+                        EventKind::Create(CreateKind::File | CreateKind::Any)
+                    ) {
                         info!("Working on files: {:?}", &events.paths);
-                        for item in events.paths {
-                            let instance = self.clone();
-                            let globals = globals.clone();
-                            pool.execute(move || {
-                                instance.conv_file(&item, &globals).unwrap();
-                            })
-                        }
+                        // Our own outputs land in the watched folder too
+                        pending.extend(
+                            events
+                                .paths
+                                .into_iter()
+                                .filter(|path| path.is_file() && !is_avif(path)),
+                        );
                     }
                 }
-                Err(errors) => log::error!("{errors:?}"),
+                Some(Err(errors)) => log::error!("{errors:?}"),
+                None => flush_at = None,
+            }
+
+            if pending.is_empty() {
+                continue;
+            }
+
+            // Keep collecting until the window started by the first file is over
+            let task_threads = match window {
+                None => 1,
+                Some(window) if !window_over => {
+                    flush_at.get_or_insert_with(|| Instant::now() + window);
+                    continue;
+                }
+                Some(_) => {
+                    let threads = calculate_tread_count(globals.threads, pending.len());
+                    debug!(
+                        "Converting a burst of {} files, {} threads each",
+                        pending.len(),
+                        threads.task_threads
+                    );
+                    threads.task_threads
+                }
+            };
+
+            let mut files: Vec<PathBuf> = pending.drain().collect();
+            files.sort();
+
+            if self.dispatch(&pool, &slots, files, task_threads, globals, &mut dispatched) {
+                break;
             }
         }
 
+        pool.join();
+
         Ok(())
     }
 
-    fn conv_file(&self, path: &Path, globals: &Globals) -> Result<()> {
-        let mut image = ImageFile::new_from_path(path)?;
+    /// Queue `files` for conversion with `task_threads` encoder threads each.
+    /// Returns `true` once `--limit` files have been dispatched
+    fn dispatch(
+        &self,
+        pool: &ThreadPool,
+        slots: &Arc<QueueSlots>,
+        files: Vec<PathBuf>,
+        task_threads: usize,
+        globals: &Globals,
+        dispatched: &mut u64,
+    ) -> bool {
+        let limit = globals.limit;
+
+        for item in files {
+            let slot = slots.acquire(self.max_queue.max(1));
+
+            let instance = self.clone();
+            let globals = globals.clone();
+            pool.execute(move || {
+                // Dropped when the job ends, even if it panics
+                let _slot = slot;
+                if let Err(error) = instance.conv_file(&item, &globals, task_threads) {
+                    error!("Failed to convert {item:?}: {error}");
+                }
+            });
+
+            *dispatched += 1;
+
+            if limit.is_some_and(|limit| *dispatched >= limit) {
+                info!("Reached the limit of {dispatched} files, stopping");
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn conv_file(&self, path: &Path, globals: &Globals, threads: usize) -> Result<()> {
+        let mut image = ImageFile::open(path, globals.input_format())?;
         let image_size = image.metadata.size;
 
+        image.dir_config = DirConfig::for_file(path);
+        let globals = &globals.with_dir_config(image.dir_config.as_ref());
+
         let start = Instant::now();
 
-        let fsz = image.convert_to_avif_stored(
-            globals.quality,
-            globals.speed,
-            1,
-            globals.bit_depth,
-            globals.remove_alpha,
-            None,
-        )?;
+        let fsz = image.convert_to_avif_stored(&globals.encode_options(threads), None)?;
+
+        if let Some(note) = globals.alpha_note(&image) {
+            info!("{note}");
+        }
+
+        if let Some(note) = globals.profile_note(&image) {
+            info!("{note}");
+        }
+
+        if let Some(note) = globals.bit_depth_note(&image) {
+            info!("{note}");
+        }
 
-        image.save_avif(None, globals.name_type, globals.keep)?;
+        let saved = image.save_avif(None, &globals.save_options())?;
+
+        if saved.duplicate {
+            info!(
+                "File '{}' is identical to {:?}, skipped",
+                truncate_str(&image.metadata.filename, 32),
+                saved.path
+            );
+        }
+
+        if let Some(command) = &globals.exec {
+            run_exec_hook(command, path, &saved.path, fsz as f64 / image_size as f64);
+        }
+
+        let elapsed = start.elapsed();
 
         info!(
             "File '{}' encode finished. {} -> {} ({:?})",
             truncate_str(&image.metadata.filename, 32),
             ByteSize::b(image_size).to_string_as(true),
             ByteSize::b(fsz).to_string_as(true),
-            start.elapsed()
+            elapsed
         );
 
+        if self.notify {
+            let console = ConsoleMsg::new(globals.verbosity(), self.notify);
+
+            console.notify_image(
+                &format!(
+                    "{} finished in {:.2?} \n {} → {}",
+                    image.metadata.filename,
+                    elapsed,
+                    ByteSize::b(image_size).to_string_as(true),
+                    ByteSize::b(fsz).to_string_as(true)
+                ),
+                image.bitmap.clone(),
+            )?;
+        }
+
         Ok(())
     }
 }
+
+/// Counts files queued in the pool or encoding, `ThreadPool::queued_count` leaves out the
+/// running ones
+#[derive(Default)]
+struct QueueSlots {
+    taken: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl QueueSlots {
+    /// Block until fewer than `max` files are queued or encoding, then take a slot
+    fn acquire(self: &Arc<Self>, max: usize) -> QueueSlot {
+        let mut taken = self.taken.lock().unwrap();
+
+        if *taken >= max {
+            debug!("Encode queue is full ({max} files), waiting");
+        }
+
+        while *taken >= max {
+            taken = self.freed.wait(taken).unwrap();
+        }
+
+        *taken += 1;
+
+        QueueSlot(self.clone())
+    }
+}
+
+/// A taken slot, given back when dropped
+struct QueueSlot(Arc<QueueSlots>);
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        *self.0.taken.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn running_jobs_hold_their_slot() {
+        let slots = Arc::new(QueueSlots::default());
+        let first = slots.acquire(2);
+        let _second = slots.acquire(2);
+
+        let (tx, rx) = mpsc::channel();
+        let waiting = slots.clone();
+        let handle = thread::spawn(move || {
+            let _third = waiting.acquire(2);
+            tx.send(()).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        drop(first);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+    }
+}
@@ -1,19 +1,59 @@
 use log::{debug, error};
-use std::sync::atomic::AtomicU64;
+use std::{
+    cell::Cell,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
 use thread_priority::{set_current_thread_priority, ThreadPriority, ThreadPriorityValue};
 
-use clap::{Parser, ValueEnum};
+use clap::{
+    error::ErrorKind, parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser,
+    ValueEnum,
+};
 
-use crate::name_fun::Name;
+use crate::{
+    config::DirConfig,
+    console::{ConsoleMsg, Verbosity},
+    encoders::avif::encode::{
+        HdrMetadata, MasteringDisplayInfo, MasteringPrimaries, Matrix, Primaries, Subsampling,
+    },
+    filter::{Crop, Filter, Flip, Rotation},
+    image_file::{effective_bit_depth, EncodeOptions, ImageFile, SaveOptions},
+    name_fun::Name,
+};
 use color_eyre::eyre::Result;
+use image::ImageFormat;
+use owo_colors::OwoColorize;
 
 use self::commands::Commands;
 
 pub mod commands;
 
+/// Qualities from here on get the `--bit-depth 8` banding advisory
+const HIGH_QUALITY: u8 = 90;
+
 static SUCCESS_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Sources converted or still encoding. `--limit` hands out no more than that many, a
+/// source that fails gives its slot back
+static LIMIT_SLOTS: AtomicU64 = AtomicU64::new(0);
 static FINAL_STATS: AtomicU64 = AtomicU64::new(0);
+/// Source bytes behind every output in `FINAL_STATS`. A source with several `--widths` or
+/// `--quality-set` outputs counts once per output
+static SOURCE_STATS: AtomicU64 = AtomicU64::new(0);
 static ITEMS_PROCESSED: AtomicU64 = AtomicU64::new(0);
+static DEDUP_COUNT: AtomicU64 = AtomicU64::new(0);
+static DEDUP_STATS: AtomicU64 = AtomicU64::new(0);
+static FAILED_COUNT: AtomicU64 = AtomicU64::new(0);
+static GROWN_COUNT: AtomicU64 = AtomicU64::new(0);
+static GROWN_STATS: AtomicU64 = AtomicU64::new(0);
+static TIMED_OUT_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Next core handed out by `--pin-threads`
+static NEXT_CORE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static PINNED: Cell<bool> = const { Cell::new(false) };
+}
 
 fn bit_values(s: &str) -> Result<u8, String> {
     const DEPTHS: [u8; 3] = [8, 10, 12];
@@ -28,6 +68,38 @@ fn bit_values(s: &str) -> Result<u8, String> {
     }
 }
 
+fn fraction_values(s: &str) -> Result<f64, String> {
+    let fraction: f64 = s
+        .parse()
+        .map_err(|_| format!("`{s}` isn't a valid number"))?;
+
+    if fraction > 0. && fraction <= 1. {
+        Ok(fraction)
+    } else {
+        Err("value must be in the (0, 1] range".to_string())
+    }
+}
+
+fn color_values(s: &str) -> Result<[u8; 3], String> {
+    match s.to_ascii_lowercase().as_str() {
+        "black" => return Ok([0, 0, 0]),
+        "white" => return Ok([255, 255, 255]),
+        _ => {}
+    }
+
+    let hex = s.strip_prefix('#').unwrap_or(s);
+
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("`{s}` isn't a RRGGBB color"));
+    }
+
+    let channel = |i: usize| {
+        u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("`{s}` isn't a RRGGBB color"))
+    };
+
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
 #[derive(Debug, Clone, Parser)]
 pub struct Args {
     #[command(subcommand)]
@@ -45,6 +117,20 @@ pub struct Args {
     #[clap(short, long, default_value_t = 4, value_name = "SPEED", global = true)]
     pub speed: u8,
 
+    /// Target bitrate in kbps instead of a fixed quality. The output size is approximate for still images
+    #[clap(long, value_name = "KBPS", conflicts_with = "quality", global = true)]
+    pub bitrate: Option<u32>,
+
+    /// Lowest quantizer (0-255, lower is better) rate control may pick. With `--quality`
+    /// the quantizer it maps to is clamped into `--min-quantizer..=--max-quantizer`
+    #[clap(long, value_name = "QUANTIZER", global = true)]
+    pub min_quantizer: Option<u8>,
+
+    /// Highest quantizer rate control may pick, a floor on quality.
+    /// Defaults to the quantizer of `--quality` in bitrate mode
+    #[clap(long, value_name = "QUANTIZER", global = true)]
+    pub max_quantizer: Option<u8>,
+
     #[clap(short, long, value_enum, default_value_t = Name::MD5, global = true)]
     pub name_type: Name,
 
@@ -68,6 +154,17 @@ pub struct Args {
     )]
     pub threads: usize,
 
+    /// Stop after this many files. Batches start no more than N sources at once and stop
+    /// once N of them converted, however many outputs each has. Watch mode exits after
+    /// this many new files
+    #[clap(long, value_name = "N", global = true)]
+    pub limit: Option<u64>,
+
+    /// Give up on a file when its encode takes longer than SECONDS. It's counted as failed
+    /// and nothing is written. The abandoned encode still runs to completion in the background
+    #[clap(long, value_name = "SECONDS", global = true)]
+    pub timeout_per_file: Option<u64>,
+
     /// How many images to keep in memory at once
     #[clap(short, long)]
     pub batch_size: Option<usize>,
@@ -76,16 +173,237 @@ pub struct Args {
     #[clap(long, default_value_t = false, global = true)]
     pub quiet: bool,
 
+    /// Hide the progress bar and per-file messages, but still print the final summary
+    #[clap(long, default_value_t = false, conflicts_with = "quiet", global = true)]
+    pub summary_only: bool,
+
+    /// Text appended to output names with `--name-type same` (`photo_compressed.avif`)
+    #[clap(long, value_name = "STR", global = true)]
+    pub suffix: Option<String>,
+
     /// Keep original file
     #[clap(short, long, default_value_t = false, global = true)]
     pub keep: bool,
 
+    /// Keep a copy of every replaced original as `<file>.orig`, e.g. `photo.jpg.orig`
+    #[clap(long, default_value_t = false, conflicts_with = "keep", global = true)]
+    pub backup: bool,
+
+    /// Append `.avif` to the original file name instead of replacing its extension (`photo.jpg.avif`)
+    #[clap(long, default_value_t = false, global = true)]
+    pub keep_extension: bool,
+
+    /// With a hash name type, don't write outputs identical to an existing file.
+    /// Without `--keep` the duplicate source is removed as well
+    #[clap(long, default_value_t = false, global = true)]
+    pub dedup: bool,
+
+    /// Give outputs the permissions of their source, and its owner when allowed
+    #[clap(long, default_value_t = false, global = true)]
+    pub preserve_perms: bool,
+
+    /// How many times reading a source or writing an output is retried after a transient
+    /// IO error, such as a network share hiccup or a file locked by another program.
+    /// Waits 100 ms before the first retry and doubles the wait every time
+    #[clap(long, default_value_t = 2, value_name = "COUNT", global = true)]
+    pub io_retries: u32,
+
+    /// Write outputs to this directory first and move them into place when done.
+    /// Useful when the source directory is slow or only writable for the final file
+    #[clap(long, value_name = "DIR", global = true)]
+    pub tmpdir: Option<PathBuf>,
+
+    /// Command to run after each converted file is saved. `{input}`, `{output}` and `{ratio}`
+    /// stand for the source path, the saved path and output size / input size. They are
+    /// passed through the environment, so file names are never run as shell code
+    #[clap(long, value_name = "COMMAND", global = true)]
+    pub exec: Option<String>,
+
+    /// Match the case of the output extension to the source (`photo.PNG` becomes `photo.AVIF`)
+    #[clap(long, default_value_t = false, global = true)]
+    pub replace_extension_case: bool,
+
     #[clap(long, default_value_t = false, global = true)]
     pub remove_alpha: bool,
 
+    /// Composite any transparency onto COLOR and always encode opaque. Takes `RRGGBB`,
+    /// `#RRGGBB`, `black` or `white`
+    #[clap(
+        long,
+        value_name = "COLOR",
+        value_parser(color_values),
+        conflicts_with = "remove_alpha",
+        global = true
+    )]
+    pub flatten: Option<[u8; 3]>,
+
+    /// Encode the alpha channel at quality 100 for clean edges on logos and stickers.
+    /// Color still uses `--quality`. Adds a few KB on images with large transparent areas
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with = "remove_alpha",
+        conflicts_with = "flatten",
+        global = true
+    )]
+    pub lossless_alpha: bool,
+
+    /// Re-read every saved file and compare it against the encoded data.
+    /// If it doesn't match, the original file is restored and the file is reported as failed
+    #[clap(long, default_value_t = false, global = true)]
+    pub verify: bool,
+
+    /// Pre-processing filter applied before encoding. Can be repeated, filters run in the given order.
+    /// [grayscale, invert, blur=SIGMA, sharpen[=SIGMA], brightness=FACTOR, contrast=VALUE]
+    #[clap(long = "filter", value_name = "FILTER", global = true)]
+    pub filters: Vec<Filter>,
+
+    /// Keep only this rectangle of every image, given as X,Y,WIDTH,HEIGHT in source pixels.
+    /// Applied first, before `--rotate`, `--flip` and `--filter`. Images it doesn't fit fail
+    #[clap(long, value_name = "X,Y,WIDTH,HEIGHT", global = true)]
+    pub crop: Option<Crop>,
+
+    /// Rotate every image clockwise by this many degrees. Runs before `--flip` and `--filter`
+    #[clap(long, value_name = "DEGREES", global = true)]
+    pub rotate: Option<Rotation>,
+
+    /// Mirror every image horizontally or vertically. Runs after `--rotate`, before `--filter`
+    #[clap(long, value_enum, global = true)]
+    pub flip: Option<Flip>,
+
+    /// Print a note for files whose alpha channel takes more than this fraction of the output.
+    /// Large noisy alpha usually means the image should be encoded opaque
+    #[clap(
+        long,
+        value_name = "FRACTION",
+        default_value_t = 0.4,
+        value_parser(fraction_values),
+        global = true
+    )]
+    pub max_alpha_overhead: f64,
+
+    /// Encode images as opaque when at most this fraction of their pixels isn't fully
+    /// opaque, e.g. stray 254 alpha from anti-aliasing
+    #[clap(
+        long,
+        value_name = "FRACTION",
+        value_parser(fraction_values),
+        conflicts_with = "remove_alpha",
+        global = true
+    )]
+    pub prefer_opaque_threshold: Option<f64>,
+
+    /// YCbCr matrix coefficients. Some players expect BT.709 for sRGB content
+    #[clap(long, value_enum, default_value_t = Matrix::Bt601, global = true)]
+    pub matrix: Matrix,
+
+    /// Color primaries to tag the output with. Use `p3` for Display P3 sources without an
+    /// ICC profile, such as screenshots from recent Macs
+    #[clap(long, value_enum, default_value_t = Primaries::Srgb, global = true)]
+    pub primaries: Primaries,
+
+    /// Chroma subsampling. 4:2:0 is much smaller for photos, where fine color detail is
+    /// rarely visible, but blurs colored edges in screenshots and line art
+    #[clap(long, value_enum, default_value_t = Subsampling::Cs444, global = true)]
+    pub subsampling: Subsampling,
+
+    /// The sources are sRGB, convert them to `--primaries p3` so colors stay the same
+    /// instead of being stretched over the wider gamut
+    #[clap(long, default_value_t = false, global = true)]
+    pub convert_gamut: bool,
+
+    /// Convert sources with an embedded ICC profile (wide gamut photos, for instance) to sRGB
+    /// and tag the output as sRGB. Without it the profile is ignored
+    #[clap(
+        long,
+        default_value_t = false,
+        conflicts_with = "primaries",
+        conflicts_with = "convert_gamut",
+        global = true
+    )]
+    pub convert_to_srgb: bool,
+
+    /// Encode a single grayscale plane, for depth maps and masks. Any color or
+    /// transparency in the source is discarded
+    #[clap(long, default_value_t = false, global = true)]
+    pub monochrome: bool,
+
+    /// HDR maximum content light level (MaxCLL) in cd/m²
+    #[clap(long, value_name = "NITS", requires = "max_fall", global = true)]
+    pub max_cll: Option<u16>,
+
+    /// HDR maximum frame-average light level (MaxFALL) in cd/m²
+    #[clap(long, value_name = "NITS", requires = "max_cll", global = true)]
+    pub max_fall: Option<u16>,
+
+    /// Primaries of the HDR mastering display
+    #[clap(long, value_enum, requires = "mastering_max_luminance", global = true)]
+    pub mastering_primaries: Option<MasteringPrimaries>,
+
+    /// Peak luminance of the HDR mastering display in cd/m²
+    #[clap(
+        long,
+        value_name = "NITS",
+        requires = "mastering_primaries",
+        global = true
+    )]
+    pub mastering_max_luminance: Option<f32>,
+
+    /// Minimum luminance of the HDR mastering display in cd/m²
+    #[clap(
+        long,
+        value_name = "NITS",
+        default_value_t = 0.005,
+        requires = "mastering_primaries",
+        global = true
+    )]
+    pub mastering_min_luminance: f32,
+
     /// Set encoder threads priority
     #[clap(short, long, value_enum, default_value_t = ThreadNice::Default, global = true)]
     pub priority: ThreadNice,
+
+    /// Pin each batch worker thread to its own CPU core, so it doesn't migrate
+    /// across NUMA nodes. rav1e's own helper threads are left alone
+    #[clap(long, default_value_t = false, global = true)]
+    pub pin_threads: bool,
+
+    /// Print the resolved rav1e settings (quantizer, tiles, partition range, enabled tools)
+    /// for every encoded plane
+    #[clap(long, default_value_t = false, global = true)]
+    pub encoder_log: bool,
+
+    /// Encode every file on a single thread with a single tile, so the same input
+    /// always produces the same bytes regardless of the machine or `--threads`
+    #[clap(long, default_value_t = false, global = true)]
+    pub deterministic: bool,
+
+    /// Treat advisories about risky flag combinations, like `--quality` 90 or more
+    /// at 8 bits, as errors
+    #[clap(long, default_value_t = false, global = true)]
+    pub strict: bool,
+
+    /// `baseline` limits output to what every AVIF decoder handles: 8-bit, 4:2:0, BT.709
+    /// and no HDR boxes. Conflicting flags are overridden with a warning
+    #[clap(long, value_enum, default_value_t = Compat::Modern, global = true)]
+    pub compat: Compat,
+
+    /// Decode explicitly listed files as FORMAT whatever their extension says. In directory
+    /// scans, files with a known extension keep their own format and the rest use FORMAT
+    #[clap(long, value_enum, value_name = "FORMAT", global = true)]
+    pub input_format: Option<InputFormat>,
+
+    /// Encode a regular one-frame AV1 sequence instead of a still picture, for experiments
+    #[clap(long, default_value_t = false, global = true, hide = true)]
+    pub no_still_picture: bool,
+
+    /// How to report progress. `json` prints one JSON object per finished file to stderr
+    #[clap(long, value_enum, default_value_t = ProgressFormat::Bar, global = true)]
+    pub progress_format: ProgressFormat,
+
+    /// Arguments explicitly set on the command line, these take precedence over config files
+    #[clap(skip)]
+    pub cli_set: Vec<String>,
 }
 
 #[derive(Debug, Copy, Clone, ValueEnum, PartialEq, Eq, PartialOrd, Ord)]
@@ -96,9 +414,327 @@ pub enum ThreadNice {
     Default = 55,
 }
 
+#[derive(Debug, Copy, Clone, ValueEnum, PartialEq, Eq)]
+pub enum Compat {
+    /// Only settings every decoder supports
+    Baseline,
+    /// Whatever the other flags ask for
+    Modern,
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum, PartialEq, Eq)]
+pub enum InputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Bmp,
+    Tiff,
+    Avif,
+}
+
+impl From<InputFormat> for ImageFormat {
+    fn from(format: InputFormat) -> Self {
+        match format {
+            InputFormat::Jpeg => ImageFormat::Jpeg,
+            InputFormat::Png => ImageFormat::Png,
+            InputFormat::Webp => ImageFormat::WebP,
+            InputFormat::Bmp => ImageFormat::Bmp,
+            InputFormat::Tiff => ImageFormat::Tiff,
+            InputFormat::Avif => ImageFormat::Avif,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum, PartialEq, Eq)]
+pub enum ProgressFormat {
+    Bar,
+    Json,
+}
+
 impl Args {
     pub fn init() -> Self {
-        Self::parse()
+        let matches = Self::command().get_matches();
+        let mut args = Self::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+        args.cli_set = ["quality", "speed", "name_type", "bit_depth"]
+            .into_iter()
+            .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+            .map(String::from)
+            .collect();
+
+        if args.compat == Compat::Baseline {
+            args.warn_compat_overrides(&matches);
+        }
+
+        args.check_quality_depth();
+
+        // `requires` can't catch this, `--primaries` always has a value
+        if args.convert_gamut
+            && args.primaries == Primaries::Srgb
+            && args.compat != Compat::Baseline
+        {
+            Self::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--convert-gamut needs --primaries p3, sRGB sources are already sRGB",
+                )
+                .exit();
+        }
+
+        if let (Some(min), Some(max)) = (args.min_quantizer, args.max_quantizer) {
+            if min > max {
+                Self::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        format!("--min-quantizer ({min}) is above --max-quantizer ({max})"),
+                    )
+                    .exit();
+            }
+        }
+
+        args
+    }
+
+    /// High qualities at 8 bits spend bytes on detail but still band in smooth gradients,
+    /// 10 bits avoids that for about the same size. Only advice, unless `--strict` is set
+    fn check_quality_depth(&self) {
+        if self.bit_depth != 8
+            || self.quality < HIGH_QUALITY
+            || self.bitrate.is_some()
+            || self.compat == Compat::Baseline
+        {
+            return;
+        }
+
+        let message = format!(
+            "--quality {} with --bit-depth 8 is prone to banding, --bit-depth 10 avoids it",
+            self.quality
+        );
+
+        if self.strict {
+            Self::command()
+                .error(ErrorKind::ArgumentConflict, message)
+                .exit();
+        }
+
+        ConsoleMsg::new(self.verbosity(), false)
+            .print_message(format!("{} {message}", "Warning:".yellow().bold()));
+    }
+
+    /// Tell the user which of their flags `--compat baseline` overrides
+    fn warn_compat_overrides(&self, matches: &ArgMatches) {
+        let overridden = [
+            ("bit_depth", self.bit_depth != 8),
+            ("matrix", self.matrix != Matrix::Bt709),
+            ("primaries", self.primaries != Primaries::Srgb),
+            ("subsampling", self.subsampling != Subsampling::Cs420),
+            ("convert_gamut", self.convert_gamut),
+            ("monochrome", self.monochrome),
+            ("max_cll", self.max_cll.is_some()),
+            ("mastering_primaries", self.mastering_primaries.is_some()),
+        ];
+
+        let console = ConsoleMsg::new(self.verbosity(), false);
+
+        // Defaults that differ from baseline, like the 10-bit depth, are overridden silently
+        for (id, _) in overridden.into_iter().filter(|&(id, conflicts)| {
+            conflicts && matches.value_source(id) == Some(ValueSource::CommandLine)
+        }) {
+            console.print_message(format!(
+                "{} --{} is ignored with --compat baseline",
+                "Warning:".yellow().bold(),
+                id.replace('_', "-")
+            ));
+        }
+    }
+
+    /// Note for files encoded without their alpha channel by `--prefer-opaque-threshold`,
+    /// or whose alpha channel is over `--max-alpha-overhead`
+    pub fn alpha_note(&self, image: &ImageFile) -> Option<String> {
+        if image.alpha_dropped {
+            return Some(format!(
+                "{}: only a few pixels weren't opaque, encoded without alpha",
+                image.original_name()
+            ));
+        }
+
+        image
+            .alpha_overhead()
+            .filter(|&overhead| overhead > self.max_alpha_overhead)
+            .map(|overhead| {
+                format!(
+                    "{}: alpha is {:.0}% of the output, consider --remove-alpha if transparency isn't needed",
+                    image.original_name(),
+                    overhead * 100.
+                )
+            })
+    }
+
+    /// Note for 8-bit sources encoded at an explicitly requested higher `--bit-depth`
+    pub fn bit_depth_note(&self, image: &ImageFile) -> Option<String> {
+        if self.bit_depth <= 8 || !self.cli_set.iter().any(|id| id == "bit_depth") {
+            return None;
+        }
+
+        let effective = effective_bit_depth(&image.bitmap);
+
+        (effective <= 8).then(|| {
+            format!(
+                "{}: the source only has {effective} bits per channel, --bit-depth 8 gives a smaller file",
+                image.original_name()
+            )
+        })
+    }
+
+    /// Warning for sources with a non-sRGB ICC profile, or CMYK pixels, that were encoded
+    /// as sRGB anyway. Setting `--primaries` counts as choosing how to tag RGB profiles,
+    /// it can't do anything for CMYK
+    pub fn profile_note(&self, image: &ImageFile) -> Option<String> {
+        let warning = image.profile_warning.as_ref()?;
+
+        if warning.convertible && self.primaries != Primaries::Srgb {
+            return None;
+        }
+
+        let hint = if warning.convertible {
+            "use --convert-to-srgb or --primaries to keep its colors"
+        } else {
+            "colors may be off"
+        };
+
+        Some(format!(
+            "{}: {warning} treated as sRGB, {hint}",
+            image.original_name()
+        ))
+    }
+
+    /// `--rotate` and `--flip`, then the `--filter` chain
+    pub fn filters(&self) -> Vec<Filter> {
+        self.rotate
+            .map(Filter::Rotate)
+            .into_iter()
+            .chain(self.flip.map(Filter::Flip))
+            .chain(self.filters.iter().copied())
+            .collect()
+    }
+
+    /// Format forced by `--input-format`
+    pub fn input_format(&self) -> Option<ImageFormat> {
+        self.input_format.map(ImageFormat::from)
+    }
+
+    /// Color transparent pixels get flattened onto, `--remove-alpha` means black
+    pub fn background(&self) -> Option<[u8; 3]> {
+        self.flatten.or(self.remove_alpha.then_some([0, 0, 0]))
+    }
+
+    pub fn encode_options(&self, threads: usize) -> EncodeOptions {
+        let options = EncodeOptions {
+            quality: self.quality,
+            speed: self.speed,
+            // One thread also means one tile, the tile count follows the thread count
+            threads: if self.deterministic { 1 } else { threads },
+            bit_depth: self.bit_depth,
+            background: self.background(),
+            lossless_alpha: self.lossless_alpha,
+            crop: self.crop,
+            filters: self.filters(),
+            bitrate: self.bitrate,
+            min_quantizer: self.min_quantizer,
+            max_quantizer: self.max_quantizer,
+            hdr: self.hdr_metadata(),
+            matrix: self.matrix,
+            primaries: self.primaries,
+            convert_gamut: self.convert_gamut,
+            convert_to_srgb: self.convert_to_srgb,
+            io_retries: self.io_retries,
+            monochrome: self.monochrome,
+            subsampling: self.subsampling,
+            still_picture: !self.no_still_picture,
+            opaque_threshold: self.prefer_opaque_threshold.unwrap_or_default(),
+            width: None,
+            timeout: self.timeout_per_file.map(Duration::from_secs),
+        };
+
+        match self.compat {
+            Compat::Modern => options,
+            Compat::Baseline => EncodeOptions {
+                bit_depth: 8,
+                matrix: Matrix::Bt709,
+                primaries: Primaries::Srgb,
+                convert_gamut: false,
+                monochrome: false,
+                subsampling: Subsampling::Cs420,
+                hdr: HdrMetadata::default(),
+                ..options
+            },
+        }
+    }
+
+    pub fn save_options(&self) -> SaveOptions {
+        SaveOptions {
+            name: self.name_type,
+            name_suffix: self.suffix.clone(),
+            keep: self.keep,
+            verify: self.verify,
+            keep_extension: self.keep_extension,
+            match_extension_case: self.replace_extension_case,
+            dedup: self.dedup,
+            tmpdir: self.tmpdir.clone(),
+            preserve_perms: self.preserve_perms,
+            backup: self.backup,
+            io_retries: self.io_retries,
+        }
+    }
+
+    pub fn hdr_metadata(&self) -> HdrMetadata {
+        HdrMetadata {
+            content_light: self.max_cll.zip(self.max_fall),
+            mastering_display: self
+                .mastering_primaries
+                .zip(self.mastering_max_luminance)
+                .map(|(primaries, max_luminance)| MasteringDisplayInfo {
+                    primaries,
+                    max_luminance,
+                    min_luminance: self.mastering_min_luminance,
+                }),
+        }
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        if self.quiet {
+            Verbosity::Quiet
+        } else if self.summary_only {
+            Verbosity::SummaryOnly
+        } else {
+            Verbosity::Normal
+        }
+    }
+
+    /// Apply a per-directory config on top of these args, without overriding CLI flags
+    pub fn with_dir_config(&self, config: Option<&DirConfig>) -> Self {
+        let mut args = self.clone();
+
+        let Some(config) = config else {
+            return args;
+        };
+
+        let from_cli = |id: &str| self.cli_set.iter().any(|set| set == id);
+
+        if let (Some(quality), false) = (config.quality, from_cli("quality")) {
+            args.quality = quality;
+        }
+        if let (Some(speed), false) = (config.speed, from_cli("speed")) {
+            args.speed = speed;
+        }
+        if let (Some(name_type), false) = (config.name_type, from_cli("name_type")) {
+            args.name_type = name_type;
+        }
+        if let (Some(bit_depth), false) = (config.bit_depth, from_cli("bit_depth")) {
+            args.bit_depth = bit_depth;
+        }
+
+        args
     }
 
     fn set_encoder_priority(thread_level: ThreadNice) {
@@ -110,4 +746,66 @@ impl Args {
             error!("Failed to set thread priority. Leaving as default")
         }
     }
+
+    /// Pin the calling worker thread to the next free core. Workers are reused
+    /// across jobs, only the first call on each thread does anything
+    fn pin_encoder_thread() {
+        if PINNED.with(|pinned| pinned.replace(true)) {
+            return;
+        }
+
+        let Some(cores) = core_affinity::get_core_ids().filter(|cores| !cores.is_empty()) else {
+            error!("Failed to list CPU cores. Leaving threads unpinned");
+            return;
+        };
+
+        let core = cores[NEXT_CORE.fetch_add(1, Ordering::SeqCst) % cores.len()];
+
+        if core_affinity::set_for_current(core) {
+            debug!("Thread pinned to core {}", core.id);
+        } else {
+            error!("Failed to pin thread to core {}", core.id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use image::RgbaImage;
+
+    use super::*;
+
+    #[test]
+    fn deterministic_encodes_are_identical() {
+        let path = env::temp_dir().join(format!("avif-converter-{}-det.png", process::id()));
+        RgbaImage::from_fn(96, 64, |x, y| {
+            image::Rgba([x as u8 * 2, y as u8 * 3, (x ^ y) as u8, 255 - x as u8])
+        })
+        .save(&path)
+        .unwrap();
+
+        let args = Args::try_parse_from([
+            "avif-converter",
+            "--deterministic",
+            "-s",
+            "10",
+            "avif",
+            path.to_str().unwrap(),
+        ])
+        .unwrap();
+        let options = args.encode_options(8);
+        assert_eq!(options.threads, 1);
+
+        let encode = || {
+            let mut image = ImageFile::open(&path, None).unwrap();
+            image.convert_to_avif_stored(&options, None).unwrap();
+            image.encoded_data
+        };
+        let (first, second) = (encode(), encode());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(first, second);
+    }
 }
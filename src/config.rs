@@ -0,0 +1,74 @@
+use std::{fs, path::Path};
+
+use log::{debug, error};
+use serde::Deserialize;
+
+use crate::name_fun::Name;
+
+/// Name of the per-directory config file
+pub const CONFIG_FILE_NAME: &str = ".avifconvert.toml";
+
+/// Per-directory defaults read from `.avifconvert.toml`.
+///
+/// Precedence is: CLI flags > nearest config file > built-in defaults.
+/// Config files in ancestor directories are merged, the nearest one wins on conflicts.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirConfig {
+    pub quality: Option<u8>,
+    pub speed: Option<u8>,
+    pub name_type: Option<Name>,
+    pub bit_depth: Option<u8>,
+}
+
+impl DirConfig {
+    /// Look for config files in `dir` and all of its ancestors
+    pub fn find(dir: &Path) -> Option<Self> {
+        let dir = dir.canonicalize().ok()?;
+
+        dir.ancestors()
+            .filter_map(Self::load)
+            .reduce(|nearest, parent| nearest.merge(parent))
+    }
+
+    /// Config that applies to the directory containing `file`
+    pub fn for_file(file: &Path) -> Option<Self> {
+        Self::find(file.canonicalize().ok()?.parent()?)
+    }
+
+    fn load(dir: &Path) -> Option<Self> {
+        let path = dir.join(CONFIG_FILE_NAME);
+        let contents = fs::read_to_string(&path).ok()?;
+
+        match toml::from_str::<Self>(&contents) {
+            Ok(config) if config.is_valid() => {
+                debug!("Loaded config {path:?}: {config:?}");
+                Some(config)
+            }
+            Ok(_) => {
+                error!("Ignoring {path:?}: values out of range");
+                None
+            }
+            Err(err) => {
+                error!("Ignoring {path:?}: {err}");
+                None
+            }
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.quality.is_none_or(|q| (1..=100).contains(&q))
+            && self.speed.is_none_or(|s| (1..=10).contains(&s))
+            && self.bit_depth.is_none_or(|d| [8, 10, 12].contains(&d))
+    }
+
+    /// Fill unset values of `self` with the ones from `other`
+    fn merge(self, other: Self) -> Self {
+        Self {
+            quality: self.quality.or(other.quality),
+            speed: self.speed.or(other.speed),
+            name_type: self.name_type.or(other.name_type),
+            bit_depth: self.bit_depth.or(other.bit_depth),
+        }
+    }
+}
@@ -1,30 +1,182 @@
-use std::time::Duration;
+use std::{
+    borrow::Cow,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use color_eyre::Result;
 use image::{imageops::FilterType, DynamicImage};
 use notify_rust::{Image, Notification};
+use serde::Serialize;
 use spinoff::{spinners, Color, Spinner, Streams};
 
-use crate::utils::PROGRESS_BAR;
+use crate::{encoders::avif::encode::Timings, utils::PROGRESS_BAR};
+
+/// Progress event printed for every finished file with `--progress-format json`
+#[derive(Debug, Serialize)]
+pub struct ProgressEvent<'a> {
+    pub done: u64,
+    pub total: u64,
+    pub file: &'a str,
+    /// AV1 payload sizes of the color and alpha channels
+    pub color_bytes: usize,
+    pub alpha_bytes: usize,
+    /// Seconds spent in each step
+    pub timings: Timings,
+    /// Why the file failed, it is counted in `done` all the same
+    pub error: Option<&'a str>,
+}
+
+impl ProgressEvent<'_> {
+    /// Print the event as a single line of JSON to stderr
+    pub fn emit(&self) {
+        if let Ok(line) = serde_json::to_string(self) {
+            eprintln!("{line}");
+        }
+    }
+}
+
+/// Final summary of a batch printed with `--stdout-summary json`
+#[derive(Debug, Serialize)]
+pub struct BatchSummary {
+    pub converted: u64,
+    pub failed: u64,
+    pub duplicates: u64,
+    pub grown: u64,
+    /// Also counted in `failed`
+    pub timed_out: u64,
+    /// Bytes
+    pub original_size: u64,
+    /// Bytes
+    pub new_size: u64,
+    /// `new_size / original_size`
+    pub ratio: f64,
+    pub elapsed_secs: f64,
+    pub average_ssim: Option<f64>,
+    pub failures: Vec<FailedFile>,
+}
+
+/// A file the batch couldn't convert, and why
+#[derive(Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FailedFile {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl BatchSummary {
+    /// Print the summary as a single line of JSON to stdout
+    pub fn print(&self) {
+        if let Ok(line) = serde_json::to_string(self) {
+            println!("{line}");
+        }
+    }
+}
+
+/// One output in the `--csv` report
+#[derive(Debug, Clone)]
+pub struct CsvRow {
+    pub file: String,
+    /// Of the output, smaller than the source with `--widths`
+    pub width: u32,
+    pub height: u32,
+    pub original_bytes: u64,
+    pub encoded_bytes: u64,
+    pub quality: u8,
+    pub ssim: Option<f64>,
+}
+
+impl CsvRow {
+    const HEADER: [&'static str; 8] = [
+        "file",
+        "width",
+        "height",
+        "original_bytes",
+        "encoded_bytes",
+        "ratio",
+        "quality",
+        "ssim",
+    ];
+
+    /// Write `rows` to `path` after a header line, empty cells for missing values
+    pub fn write_all(path: &Path, rows: &[CsvRow]) -> Result<()> {
+        let mut writer = csv::Writer::from_path(path)?;
+        Self::write_rows(&mut writer, rows)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    fn write_rows<W: Write>(writer: &mut csv::Writer<W>, rows: &[CsvRow]) -> Result<()> {
+        writer.write_record(Self::HEADER)?;
+
+        for row in rows {
+            // An empty source has no ratio
+            let ratio = (row.original_bytes > 0).then(|| {
+                format!(
+                    "{:.4}",
+                    row.encoded_bytes as f64 / row.original_bytes as f64
+                )
+            });
+
+            writer.write_record([
+                row.file.clone(),
+                row.width.to_string(),
+                row.height.to_string(),
+                row.original_bytes.to_string(),
+                row.encoded_bytes.to_string(),
+                ratio.unwrap_or_default(),
+                row.quality.to_string(),
+                row.ssim
+                    .map(|ssim| format!("{ssim:.4}"))
+                    .unwrap_or_default(),
+            ])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// How much gets printed to the console
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Spinners, progress bar, messages and the final summary
+    Normal,
+    /// Only the final summary
+    SummaryOnly,
+    /// Nothing at all
+    Quiet,
+}
 
 pub struct ConsoleMsg {
     spinner: Option<Spinner>,
-    quiet: bool,
+    verbosity: Verbosity,
     notify: bool,
 }
 
 impl ConsoleMsg {
     #[must_use]
-    pub fn new(quiet: bool, notify: bool) -> Self {
+    pub fn new(verbosity: Verbosity, notify: bool) -> Self {
         Self {
             spinner: None,
-            quiet,
+            verbosity,
             notify,
         }
     }
 
-    pub fn set_spinner(&mut self, message: &'static str) {
-        if !self.quiet {
+    fn quiet(&self) -> bool {
+        self.verbosity != Verbosity::Normal
+    }
+
+    /// Start a spinner with `message`, or update the text of the one already running.
+    pub fn set_spinner(&mut self, message: impl Into<Cow<'static, str>>) {
+        if self.quiet() {
+            return;
+        }
+
+        if let Some(spinner) = self.spinner.as_mut() {
+            spinner.update_text(message);
+        } else {
             let spinner =
                 Spinner::new_with_stream(spinners::Dots, message, Color::Green, Streams::Stderr);
 
@@ -41,14 +193,32 @@ impl ConsoleMsg {
         self
     }
 
-    pub fn print_message(&self, message: String) {
-        if !self.quiet {
+    /// Finish the spinner with the final result. Unlike `finish_spinner`, the message
+    /// is still printed with `--summary-only`
+    pub fn finish_summary(self, message: &str) -> Self {
+        if self.verbosity == Verbosity::SummaryOnly {
             println!("{message}");
         }
+
+        self.finish_spinner(message)
+    }
+
+    /// Print final results, shown unless `--quiet` is set
+    pub fn print_summary(&self, message: String) {
+        if self.verbosity != Verbosity::Quiet {
+            println!("{message}");
+        }
+    }
+
+    pub fn print_message(&self, message: String) {
+        if !self.quiet() {
+            // Keep the line from being drawn over by a running progress bar
+            PROGRESS_BAR.suspend(|| println!("{message}"));
+        }
     }
 
     pub fn setup_bar(&self, len: u64) {
-        if !self.quiet {
+        if !self.quiet() {
             PROGRESS_BAR.set_length(len);
 
             PROGRESS_BAR.enable_steady_tick(Duration::from_millis(100));
@@ -56,7 +226,7 @@ impl ConsoleMsg {
     }
 
     pub fn finish_bar(&self) {
-        if !self.quiet {
+        if !self.quiet() {
             PROGRESS_BAR.finish_and_clear();
         }
     }
@@ -105,3 +275,46 @@ impl ConsoleMsg {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_csv(rows: &[CsvRow]) -> String {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        CsvRow::write_rows(&mut writer, rows).unwrap();
+
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    fn row(file: &str, original_bytes: u64) -> CsvRow {
+        CsvRow {
+            file: file.to_string(),
+            width: 64,
+            height: 48,
+            original_bytes,
+            encoded_bytes: 500,
+            quality: 70,
+            ssim: None,
+        }
+    }
+
+    #[test]
+    fn csv_quotes_odd_names() {
+        let csv = to_csv(&[row("plain.png", 1000), row("a, \"b\"\n.png", 2000)]);
+
+        assert_eq!(
+            csv,
+            "file,width,height,original_bytes,encoded_bytes,ratio,quality,ssim\n\
+             plain.png,64,48,1000,500,0.5000,70,\n\
+             \"a, \"\"b\"\"\n.png\",64,48,2000,500,0.2500,70,\n"
+        );
+    }
+
+    #[test]
+    fn csv_leaves_ratio_of_empty_source_blank() {
+        let csv = to_csv(&[row("empty.png", 0)]);
+
+        assert_eq!(csv.lines().nth(1), Some("empty.png,64,48,0,500,,70,"));
+    }
+}
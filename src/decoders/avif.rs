@@ -0,0 +1,22 @@
+use std::{io::Cursor, path::Path};
+
+use color_eyre::eyre::Result;
+use image::{DynamicImage, ImageFormat, ImageReader};
+
+/// Decode an AVIF file into a bitmap
+pub fn get_avif_bitmap(path: &Path) -> Result<DynamicImage> {
+    let mut image_data = ImageReader::open(path)?;
+
+    image_data.set_format(ImageFormat::Avif);
+
+    Ok(image_data.decode()?)
+}
+
+/// Decode AVIF data held in memory into a bitmap
+pub fn decode_avif_bytes(data: &[u8]) -> Result<DynamicImage> {
+    let mut image_data = ImageReader::new(Cursor::new(data));
+
+    image_data.set_format(ImageFormat::Avif);
+
+    Ok(image_data.decode()?)
+}
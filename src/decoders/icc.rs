@@ -0,0 +1,146 @@
+use color_eyre::eyre::{eyre, Result};
+use image::{DynamicImage, ExtendedColorType};
+use moxcms::{ColorProfile, DataColorSpace, Layout, ProfileText, TransformOptions, Xyzd};
+use std::fmt;
+
+/// Convert `image` from the color space of the `icc` profile to sRGB.
+///
+/// 16-bit and float sources are converted at 16 bits, everything else at 8 bits.
+/// Alpha is carried over untouched. Only RGB profiles are supported.
+pub fn convert_to_srgb(image: &DynamicImage, icc: &[u8]) -> Result<DynamicImage> {
+    let source = ColorProfile::new_from_slice(icc).map_err(|err| eyre!("{err:?}"))?;
+    let srgb = ColorProfile::new_srgb();
+
+    let color = image.color();
+    let layout = if color.has_alpha() {
+        Layout::Rgba
+    } else {
+        Layout::Rgb
+    };
+
+    if color.bytes_per_pixel() > color.channel_count() {
+        let transform = source
+            .create_transform_16bit(layout, &srgb, layout, TransformOptions::default())
+            .map_err(|err| eyre!("{err:?}"))?;
+
+        return Ok(if color.has_alpha() {
+            let mut pixels = image.to_rgba16();
+            let input = pixels.as_raw().clone();
+            transform
+                .transform(&input, &mut pixels)
+                .map_err(|err| eyre!("{err:?}"))?;
+            DynamicImage::ImageRgba16(pixels)
+        } else {
+            let mut pixels = image.to_rgb16();
+            let input = pixels.as_raw().clone();
+            transform
+                .transform(&input, &mut pixels)
+                .map_err(|err| eyre!("{err:?}"))?;
+            DynamicImage::ImageRgb16(pixels)
+        });
+    }
+
+    let transform = source
+        .create_transform_8bit(layout, &srgb, layout, TransformOptions::default())
+        .map_err(|err| eyre!("{err:?}"))?;
+
+    Ok(if color.has_alpha() {
+        let mut pixels = image.to_rgba8();
+        let input = pixels.as_raw().clone();
+        transform
+            .transform(&input, &mut pixels)
+            .map_err(|err| eyre!("{err:?}"))?;
+        DynamicImage::ImageRgba8(pixels)
+    } else {
+        let mut pixels = image.to_rgb8();
+        let input = pixels.as_raw().clone();
+        transform
+            .transform(&input, &mut pixels)
+            .map_err(|err| eyre!("{err:?}"))?;
+        DynamicImage::ImageRgb8(pixels)
+    })
+}
+
+/// Colorants further than this from sRGB's mean the profile has different primaries
+const COLORANT_TOLERANCE: f64 = 0.005;
+
+/// An embedded profile, or untagged pixels, whose colors shift when encoded as sRGB
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileWarning {
+    /// Color space of the profile, like `RGB` or `CMYK`
+    pub color_space: String,
+    /// The color space comes from an embedded profile rather than the source pixels
+    pub embedded: bool,
+    /// Name from the profile's description tag
+    pub description: Option<String>,
+    /// `--convert-to-srgb` can map it to sRGB, only true for RGB profiles
+    pub convertible: bool,
+}
+
+impl fmt::Display for ProfileWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.description {
+            _ if !self.embedded => write!(f, "{} pixels without a profile", self.color_space),
+            Some(description) => write!(f, "{} profile \"{description}\"", self.color_space),
+            None => write!(f, "{} profile", self.color_space),
+        }
+    }
+}
+
+/// Check whether the `icc` profile describes something other than sRGB or grayscale.
+/// Unreadable profiles are reported too, their pixels can be in any color space
+pub fn profile_warning(icc: &[u8]) -> Option<ProfileWarning> {
+    let Ok(profile) = ColorProfile::new_from_slice(icc) else {
+        return Some(ProfileWarning {
+            color_space: "unreadable ICC".to_string(),
+            embedded: true,
+            description: None,
+            convertible: false,
+        });
+    };
+
+    let srgb = ColorProfile::new_srgb();
+    let close = |a: Xyzd, b: Xyzd| {
+        [a.x - b.x, a.y - b.y, a.z - b.z]
+            .iter()
+            .all(|d| d.abs() < COLORANT_TOLERANCE)
+    };
+
+    match profile.color_space {
+        DataColorSpace::Gray => return None,
+        DataColorSpace::Rgb
+            if close(profile.red_colorant, srgb.red_colorant)
+                && close(profile.green_colorant, srgb.green_colorant)
+                && close(profile.blue_colorant, srgb.blue_colorant) =>
+        {
+            return None
+        }
+        _ => {}
+    }
+
+    let description = profile.description.as_ref().and_then(|text| match text {
+        ProfileText::PlainString(text) => Some(text.clone()),
+        ProfileText::Localizable(records) => records.first().map(|record| record.value.clone()),
+        ProfileText::Description(description) => Some(description.ascii_string.clone()),
+    });
+
+    Some(ProfileWarning {
+        color_space: format!("{:?}", profile.color_space).to_uppercase(),
+        embedded: true,
+        description: description
+            .map(|text| text.trim_end_matches('\0').trim().to_string())
+            .filter(|text| !text.is_empty()),
+        convertible: profile.color_space == DataColorSpace::Rgb,
+    })
+}
+
+/// Check the color type the source was stored in, for files without a profile. The decoder
+/// converts CMYK to RGB without any color management, so the result is only approximate
+pub fn color_type_warning(color: ExtendedColorType) -> Option<ProfileWarning> {
+    matches!(color, ExtendedColorType::Cmyk8 | ExtendedColorType::Cmyk16).then(|| ProfileWarning {
+        color_space: "CMYK".to_string(),
+        embedded: false,
+        description: None,
+        convertible: false,
+    })
+}
@@ -0,0 +1,85 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use color_eyre::eyre::{bail, Result};
+
+/// Number of color components in the frame header of a JPEG. CMYK and YCCK files have 4,
+/// the decoder turns those into RGB and reports them as RGB
+pub fn component_count(path: &Path) -> Result<u8> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut soi = [0; 2];
+    reader.read_exact(&mut soi)?;
+    if soi != [0xFF, 0xD8] {
+        bail!("Not a JPEG file");
+    }
+
+    loop {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        if byte[0] != 0xFF {
+            bail!("Invalid JPEG marker");
+        }
+
+        // Markers may be padded with any number of 0xFF bytes
+        let mut marker = 0xFF;
+        while marker == 0xFF {
+            reader.read_exact(&mut byte)?;
+            marker = byte[0];
+        }
+
+        match marker {
+            // Standalone markers carry no length
+            0x01 | 0xD0..=0xD7 => continue,
+            0xDA | 0xD9 => bail!("JPEG has no frame header"),
+            _ => {}
+        }
+
+        let mut length = [0; 2];
+        reader.read_exact(&mut length)?;
+        let length = u16::from_be_bytes(length);
+
+        // SOF0 to SOF15, except DHT, JPG and DAC which share the range
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            // Precision, height and width come before the component count
+            let mut header = [0; 6];
+            reader.read_exact(&mut header)?;
+            return Ok(header[5]);
+        }
+
+        reader.seek_relative(i64::from(length) - 2)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use image::{codecs::jpeg::JpegEncoder, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn counts_cmyk_and_rgb_components() {
+        let cmyk = Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/cmyk-cyan.jpg"
+        ));
+        assert_eq!(component_count(cmyk).unwrap(), 4);
+
+        let path = env::temp_dir().join(format!("avif-converter-{}-rgb.jpg", process::id()));
+        let mut data = Vec::new();
+        JpegEncoder::new(&mut data)
+            .encode_image(&RgbImage::new(32, 16))
+            .unwrap();
+        fs::write(&path, data).unwrap();
+
+        let count = component_count(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(count.unwrap(), 3);
+    }
+}
@@ -0,0 +1,6 @@
+pub mod avif;
+pub mod icc;
+pub mod jpeg;
+#[cfg(feature = "raw")]
+pub mod raw;
+pub mod tiff;
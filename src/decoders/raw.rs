@@ -0,0 +1,24 @@
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Result};
+use image::{DynamicImage, RgbImage};
+
+/// Camera raw extensions accepted when the `raw` feature is enabled
+pub const RAW_EXTENSIONS: &[&str] = &["dng", "cr2", "nef"];
+
+pub fn is_raw_extension(ext: &str) -> bool {
+    RAW_EXTENSIONS.contains(&ext.to_lowercase().as_str())
+}
+
+/// Develop a camera raw file into an 8-bit sRGB bitmap with the default `imagepipe` pipeline
+pub fn decode_raw(path: &Path) -> Result<DynamicImage> {
+    let developed = imagepipe::simple_decode_8bit(path, 0, 0).map_err(|err| eyre!(err))?;
+
+    RgbImage::from_raw(
+        developed.width as u32,
+        developed.height as u32,
+        developed.data,
+    )
+    .map(DynamicImage::ImageRgb8)
+    .ok_or_else(|| eyre!("Raw decoder returned an invalid pixel buffer"))
+}
@@ -0,0 +1,59 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use ::tiff::{
+    decoder::{Decoder, DecodingResult},
+    ColorType,
+};
+use color_eyre::eyre::{bail, eyre, Result};
+use image::{DynamicImage, ImageBuffer};
+
+/// Number of pages (IFDs) stored in a TIFF file
+pub fn page_count(path: &Path) -> Result<usize> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+
+    let mut count = 1;
+    while decoder.more_images() {
+        decoder.next_image()?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Decode a single page of a multi-page TIFF. `page` is zero-based.
+pub fn decode_page(path: &Path, page: usize) -> Result<DynamicImage> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    decoder.seek_to_image(page)?;
+
+    let (width, height) = decoder.dimensions()?;
+
+    let image = match (decoder.colortype()?, decoder.read_image()?) {
+        (ColorType::Gray(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::GrayA(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLumaA16)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(buf)) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba16)
+        }
+        (color, _) => bail!("Unsupported TIFF page color type {color:?}"),
+    };
+
+    image.ok_or_else(|| eyre!("TIFF page {} has an invalid pixel buffer", page + 1))
+}
@@ -2,6 +2,7 @@ use imgref::Img;
 use imgref::ImgRef;
 use rgb::ComponentMap;
 use rgb::RGB;
+use rgb::RGBA;
 use rgb::RGBA8;
 
 #[inline]
@@ -52,6 +53,43 @@ pub(crate) fn blurred_dirty_alpha(img: ImgRef<RGBA8>) -> Option<Img<Vec<RGBA8>>>
     Some(blur_transparent_pixels(img2.as_ref()))
 }
 
+/// 16-bit version of [`blurred_dirty_alpha`]. The cleanup runs on an 8-bit copy and only
+/// fully transparent pixels take its colors, so every visible pixel keeps its full precision
+pub(crate) fn blurred_dirty_alpha_16(img: ImgRef<RGBA<u16>>) -> Option<Img<Vec<RGBA<u16>>>> {
+    // Alpha keeps its opaque/transparent/in-between class, that's what the cleanup goes by
+    let narrow: Vec<RGBA8> = img
+        .pixels()
+        .map(|px| RGBA8 {
+            r: (px.r >> 8) as u8,
+            g: (px.g >> 8) as u8,
+            b: (px.b >> 8) as u8,
+            a: match px.a {
+                0 => 0,
+                u16::MAX => 255,
+                a => ((a >> 8) as u8).clamp(1, 254),
+            },
+        })
+        .collect();
+
+    let cleaned = blurred_dirty_alpha(Img::new(narrow.as_slice(), img.width(), img.height()))?;
+
+    let out = img
+        .pixels()
+        .zip(cleaned.pixels())
+        .map(|(px, clean)| match px.a {
+            0 => RGBA::new(
+                u16::from(clean.r) * 257,
+                u16::from(clean.g) * 257,
+                u16::from(clean.b) * 257,
+                0,
+            ),
+            _ => px,
+        })
+        .collect();
+
+    Some(Img::new(out, img.width(), img.height()))
+}
+
 /// copy color from opaque pixels to transparent pixels
 /// (so that when edges get crushed by compression, the distortion will be away from visible edge)
 fn bleed_opaque_color(img: ImgRef<RGBA8>, bg: RGBA8) -> Img<Vec<RGBA8>> {
@@ -141,3 +179,33 @@ fn premultiplied_minmax(px: u8, alpha: u8) -> (u8, u8) {
 
     (low.min(px), hi.max(px))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transparent_16_bit_garbage_is_cleared() {
+        // Opaque red on the left, an antialiased edge, invisible noise on the right
+        let pixels: Vec<RGBA<u16>> = (0..64 * 32)
+            .map(|i| match i % 64 {
+                0..=31 => RGBA::new(60000, 1000, 1000, u16::MAX),
+                32 => RGBA::new(60000, 1000, 1000, 32768),
+                x => RGBA::new((x * 997 % 65536) as u16, (i * 31 % 65536) as u16, 12345, 0),
+            })
+            .collect();
+
+        let cleaned = blurred_dirty_alpha_16(Img::new(pixels.as_slice(), 64, 32)).unwrap();
+
+        for (px, clean) in pixels.iter().zip(cleaned.pixels()) {
+            if px.a != 0 {
+                assert_eq!(*px, clean);
+            } else {
+                assert_eq!(clean.a, 0);
+                // 8-bit red widened back to 16 bits, the noise is gone
+                assert_eq!(clean.b % 257, 0);
+                assert_ne!(clean.b, 12345);
+            }
+        }
+    }
+}
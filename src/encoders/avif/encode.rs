@@ -1,14 +1,20 @@
-use std::time::Instant;
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
 use imgref::Img;
-use log::{debug, trace};
-use rav1e::prelude::*;
-use rgb::{FromSlice, RGB, RGBA};
+use log::{debug, info, trace};
+use rav1e::{prelude::*, CastFromPrimitive};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rgb::{ComponentMap, FromSlice, RGB, RGBA};
+use serde::Serialize;
 
 use crate::image_file::ImageFile;
 
-use super::alpha::blurred_dirty_alpha;
+use super::alpha::{blurred_dirty_alpha, blurred_dirty_alpha_16};
 use super::error::Error;
 
 /// The newly-created image file + extra info FYI
@@ -18,9 +24,252 @@ pub struct EncodedImage {
     /// AVIF (HEIF+AV1) encoded image data
     pub avif_file: Vec<u8>,
     /// FYI: number of bytes of AV1 payload used for the color
-    pub _color_byte_size: usize,
+    pub color_byte_size: usize,
     /// FYI: number of bytes of AV1 payload used for the alpha channel
-    pub _alpha_byte_size: usize,
+    pub alpha_byte_size: usize,
+    /// The source had a few non-opaque pixels, but few enough to be encoded as opaque
+    pub alpha_dropped: bool,
+    /// Time spent in each step, `decode` is left for the caller to fill in
+    pub timings: Timings,
+}
+
+/// Where the time of one encode went
+#[derive(Debug, Copy, Clone, Default, Serialize)]
+pub struct Timings {
+    /// Decoding the source and applying filters
+    #[serde(serialize_with = "as_secs")]
+    pub decode: Duration,
+    /// Converting RGB pixels to YCbCr planes
+    #[serde(serialize_with = "as_secs")]
+    pub convert: Duration,
+    /// AV1 encode of the color channels
+    #[serde(serialize_with = "as_secs")]
+    pub color: Duration,
+    /// AV1 encode of the alpha channel, zero for opaque outputs
+    #[serde(serialize_with = "as_secs")]
+    pub alpha: Duration,
+}
+
+fn as_secs<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// YCbCr matrix used to convert RGB pixels, signalled in both the AV1 stream and the container
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Matrix {
+    #[default]
+    Bt601,
+    Bt709,
+    Bt2020ncl,
+}
+
+impl Matrix {
+    /// Kr, Kg, Kb luma weights
+    fn luma_weights(self) -> [f32; 3] {
+        match self {
+            Matrix::Bt601 => [0.2990, 0.5870, 0.1140],
+            Matrix::Bt709 => [0.2126, 0.7152, 0.0722],
+            Matrix::Bt2020ncl => [0.2627, 0.6780, 0.0593],
+        }
+    }
+
+    fn rav1e(self) -> MatrixCoefficients {
+        match self {
+            Matrix::Bt601 => MatrixCoefficients::BT601,
+            Matrix::Bt709 => MatrixCoefficients::BT709,
+            Matrix::Bt2020ncl => MatrixCoefficients::BT2020NCL,
+        }
+    }
+
+    fn container(self) -> avif_serialize::constants::MatrixCoefficients {
+        use avif_serialize::constants::MatrixCoefficients as Container;
+
+        match self {
+            Matrix::Bt601 => Container::Bt601,
+            Matrix::Bt709 => Container::Bt709,
+            Matrix::Bt2020ncl => Container::Bt2020Ncl,
+        }
+    }
+}
+
+/// Chroma resolution of the color channel, relative to luma
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Subsampling {
+    /// Full resolution chroma
+    #[default]
+    #[value(name = "444")]
+    Cs444,
+    /// Half the horizontal chroma resolution
+    #[value(name = "422")]
+    Cs422,
+    /// Half the chroma resolution in both directions, the most widely supported
+    #[value(name = "420")]
+    Cs420,
+}
+
+impl From<Subsampling> for ChromaSampling {
+    fn from(subsampling: Subsampling) -> Self {
+        match subsampling {
+            Subsampling::Cs444 => ChromaSampling::Cs444,
+            Subsampling::Cs422 => ChromaSampling::Cs422,
+            Subsampling::Cs420 => ChromaSampling::Cs420,
+        }
+    }
+}
+
+/// Color primaries the RGB values are expressed in, both use the sRGB transfer curve
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Primaries {
+    #[default]
+    Srgb,
+    /// Display P3, what wide gamut Apple displays and their screenshots use
+    P3,
+}
+
+impl Primaries {
+    fn rav1e(self) -> ColorPrimaries {
+        match self {
+            Primaries::Srgb => ColorPrimaries::BT709,
+            Primaries::P3 => ColorPrimaries::SMPTE432,
+        }
+    }
+
+    fn container(self) -> avif_serialize::constants::ColorPrimaries {
+        use avif_serialize::constants::ColorPrimaries as Container;
+
+        match self {
+            Primaries::Srgb => Container::Bt709,
+            Primaries::P3 => Container::DisplayP3,
+        }
+    }
+}
+
+/// Linear sRGB to linear Display P3, both D65
+const SRGB_TO_P3: [[f32; 3]; 3] = [
+    [0.822_462_1, 0.177_538, 0.],
+    [0.033_194_1, 0.966_805_8, 0.],
+    [0.017_082_7, 0.072_397_4, 0.910_519_9],
+];
+
+/// Rewrite interleaved sRGB pixels as Display P3 values that look the same.
+/// Alpha, when `channels` is 4, is left alone.
+fn srgb_to_display_p3(pixels: &mut [u8], channels: usize) {
+    let to_linear = |v: f32| {
+        if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let from_linear = |v: f32| {
+        let v = v.clamp(0., 1.);
+        let v = if v <= 0.003_130_8 {
+            v * 12.92
+        } else {
+            1.055 * v.powf(1. / 2.4) - 0.055
+        };
+        (v * 255.).round() as u8
+    };
+
+    let linear: Vec<f32> = (0..=255u8)
+        .map(|v| to_linear(f32::from(v) / 255.))
+        .collect();
+
+    for px in pixels.chunks_exact_mut(channels) {
+        let rgb = [
+            linear[px[0] as usize],
+            linear[px[1] as usize],
+            linear[px[2] as usize],
+        ];
+
+        for (out, row) in px.iter_mut().zip(SRGB_TO_P3) {
+            *out = from_linear(row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]);
+        }
+    }
+}
+
+/// Primaries of the display an HDR image was mastered on
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum MasteringPrimaries {
+    Bt709,
+    P3,
+    Bt2020,
+}
+
+impl MasteringPrimaries {
+    /// CIE 1931 xy chromaticities in red, green, blue order
+    fn chromaticities(self) -> [(f32, f32); 3] {
+        match self {
+            MasteringPrimaries::Bt709 => [(0.640, 0.330), (0.300, 0.600), (0.150, 0.060)],
+            MasteringPrimaries::P3 => [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060)],
+            MasteringPrimaries::Bt2020 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046)],
+        }
+    }
+}
+
+/// D65 white point, used by all the supported mastering primaries
+const WHITE_POINT_D65: (f32, f32) = (0.3127, 0.3290);
+
+/// SMPTE ST 2086 mastering display color volume
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MasteringDisplayInfo {
+    pub primaries: MasteringPrimaries,
+    /// cd/m²
+    pub max_luminance: f32,
+    /// cd/m²
+    pub min_luminance: f32,
+}
+
+/// HDR metadata, signalled both in the AV1 sequence header and in the AVIF container
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct HdrMetadata {
+    /// MaxCLL and MaxFALL in cd/m²
+    pub content_light: Option<(u16, u16)>,
+    pub mastering_display: Option<MasteringDisplayInfo>,
+}
+
+impl HdrMetadata {
+    fn rav1e_content_light(&self) -> Option<ContentLight> {
+        self.content_light.map(|(max_cll, max_fall)| ContentLight {
+            max_content_light_level: max_cll,
+            max_frame_average_light_level: max_fall,
+        })
+    }
+
+    fn rav1e_mastering_display(&self) -> Option<MasteringDisplay> {
+        // rav1e takes 0.16 fixed-point chromaticities and 24.8 / 18.14 fixed-point luminance
+        let point = |(x, y): (f32, f32)| ChromaticityPoint {
+            x: (x * 65536.).round() as u16,
+            y: (y * 65536.).round() as u16,
+        };
+
+        self.mastering_display.map(|md| MasteringDisplay {
+            primaries: md.primaries.chromaticities().map(point),
+            white_point: point(WHITE_POINT_D65),
+            max_luminance: (md.max_luminance * 256.).round() as u32,
+            min_luminance: (md.min_luminance * 16384.).round() as u32,
+        })
+    }
+
+    fn apply_to_container(&self, aviffy: &mut avif_serialize::Aviffy) {
+        if let Some((max_cll, max_fall)) = self.content_light {
+            aviffy.set_content_light_level(max_cll, max_fall);
+        }
+
+        if let Some(md) = self.mastering_display {
+            // The container wants xy * 50000 in green, blue, red order and luminance * 10000
+            let point =
+                |(x, y): (f32, f32)| ((x * 50000.).round() as u16, (y * 50000.).round() as u16);
+            let [red, green, blue] = md.primaries.chromaticities().map(point);
+
+            aviffy.set_mastering_display(
+                [green, blue, red],
+                point(WHITE_POINT_D65),
+                (md.max_luminance * 10000.).round() as u32,
+                (md.min_luminance * 10000.).round() as u32,
+            );
+        }
+    }
 }
 
 /// Encoder config builder
@@ -36,6 +285,29 @@ pub struct Encoder {
     threads: usize,
     /// Bit-depth of image pixels
     bit_depth: u8,
+    /// Bounds for the color quantizer, 0-255 scale
+    min_quantizer: Option<u8>,
+    max_quantizer: Option<u8>,
+    /// Target bitrate in kbps for the color channel. `None` uses constant quantizer mode
+    bitrate: Option<u32>,
+    /// HDR mastering display and content light level
+    hdr: HdrMetadata,
+    /// RGB to YCbCr conversion matrix
+    matrix: Matrix,
+    /// Images with at most this fraction of non-opaque pixels are encoded without alpha
+    opaque_threshold: f64,
+    /// Give up on encodes running longer than this
+    timeout: Option<Duration>,
+    /// Primaries signalled for the color channel
+    primaries: Primaries,
+    /// The input is sRGB and has to be converted to `primaries` first
+    convert_gamut: bool,
+    /// Encode luma only, as a single plane
+    monochrome: bool,
+    /// Chroma resolution of the color channel, 4:4:4 keeps it whole
+    chroma_sampling: ChromaSampling,
+    /// Signal an AV1 still picture. Off means a regular one-frame sequence
+    still_picture: bool,
 }
 
 /// Builder methods
@@ -46,9 +318,21 @@ impl Encoder {
         Self {
             quantizer: quality_to_quantizer(80.),
             alpha_quantizer: quality_to_quantizer(80.),
+            min_quantizer: None,
+            max_quantizer: None,
             speed: 5,
             threads: num_cpus::get(),
             bit_depth: 10,
+            bitrate: None,
+            hdr: HdrMetadata::default(),
+            matrix: Matrix::default(),
+            opaque_threshold: 0.,
+            timeout: None,
+            primaries: Primaries::default(),
+            convert_gamut: false,
+            monochrome: false,
+            chroma_sampling: ChromaSampling::Cs444,
+            still_picture: true,
         }
     }
 
@@ -102,6 +386,117 @@ impl Encoder {
         self.bit_depth = depth;
         self
     }
+
+    /// Encode the color channel in bitrate mode, targeting `kbps` instead of a fixed quality.
+    /// For still pictures the resulting size is only approximate.
+    #[inline(always)]
+    #[track_caller]
+    #[must_use]
+    pub fn with_bitrate(mut self, kbps: Option<u32>) -> Self {
+        self.bitrate = kbps;
+        self
+    }
+
+    /// Bounds for the color quantizer, `0..=255` where lower is better quality.
+    /// In bitrate mode rate control stays within them, `max` defaulting to the quality's
+    /// quantizer. Otherwise the fixed quantizer is clamped into the range. Panics if `min > max`.
+    #[inline(always)]
+    #[track_caller]
+    #[must_use]
+    pub fn with_quantizer_range(mut self, min: Option<u8>, max: Option<u8>) -> Self {
+        assert!(min.unwrap_or(0) <= max.unwrap_or(255));
+        self.min_quantizer = min;
+        self.max_quantizer = max;
+        self
+    }
+
+    /// HDR mastering display and content light level metadata. Only signalled, pixels are untouched.
+    #[inline(always)]
+    #[track_caller]
+    #[must_use]
+    pub fn with_hdr_metadata(mut self, hdr: HdrMetadata) -> Self {
+        self.hdr = hdr;
+        self
+    }
+
+    /// Matrix coefficients for the RGB to YCbCr conversion. BT.601 unless set.
+    #[inline(always)]
+    #[track_caller]
+    #[must_use]
+    pub fn with_matrix(mut self, matrix: Matrix) -> Self {
+        self.matrix = matrix;
+        self
+    }
+
+    /// Drop the alpha channel when at most this fraction of pixels isn't fully opaque,
+    /// e.g. a few anti-aliasing leftovers. 0 keeps alpha whenever any pixel needs it.
+    #[inline(always)]
+    #[track_caller]
+    #[must_use]
+    pub fn with_opaque_threshold(mut self, fraction: f64) -> Self {
+        assert!((0. ..=1.).contains(&fraction));
+        self.opaque_threshold = fraction;
+        self
+    }
+
+    /// Fail with [`Error::TimedOut`] when [`Encoder::encode`] takes longer than this.
+    /// rav1e can't be interrupted, so the abandoned encode finishes in the background.
+    #[inline(always)]
+    #[track_caller]
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Color primaries signalled in the AV1 stream and the container. Pixels are untouched
+    /// unless [`Encoder::with_gamut_conversion`] is set too.
+    #[inline(always)]
+    #[track_caller]
+    #[must_use]
+    pub fn with_primaries(mut self, primaries: Primaries) -> Self {
+        self.primaries = primaries;
+        self
+    }
+
+    /// Treat the input as sRGB and convert it to the configured primaries,
+    /// so it looks the same instead of being reinterpreted in the wider gamut.
+    #[inline(always)]
+    #[track_caller]
+    #[must_use]
+    pub fn with_gamut_conversion(mut self, convert: bool) -> Self {
+        self.convert_gamut = convert;
+        self
+    }
+
+    /// Encode a single luma plane whatever the input color type is.
+    /// Color and alpha are discarded.
+    #[inline(always)]
+    #[track_caller]
+    #[must_use]
+    pub fn with_monochrome(mut self, monochrome: bool) -> Self {
+        self.monochrome = monochrome;
+        self
+    }
+
+    /// Subsample chroma to 4:2:2 or 4:2:0. Smaller, and 4:2:0 decodes everywhere,
+    /// but colored edges blur. Use [`Encoder::with_monochrome`] instead of 4:0:0.
+    #[inline(always)]
+    #[must_use]
+    pub fn with_chroma_subsampling(mut self, sampling: Subsampling) -> Self {
+        self.chroma_sampling = sampling.into();
+        self
+    }
+
+    /// Encode as an AV1 still picture (the default). Turning it off encodes a regular
+    /// sequence with a single frame, which some decoders handle differently.
+    #[inline(always)]
+    #[track_caller]
+    #[must_use]
+    pub fn with_still_picture(mut self, still_picture: bool) -> Self {
+        self.still_picture = still_picture;
+        self
+    }
 }
 
 /// Once done with config, call one of the `encode_*` functions
@@ -132,17 +527,21 @@ impl Encoder {
 
         let width = buffer.width();
         let height = buffer.height();
+        let weights = self.matrix.luma_weights();
 
         match self.bit_depth {
             8 => {
-                let planes = buffer.pixels().map(|px| rgb_to_8_bit_ycbcr(px.rgb()));
+                let ycbcr = FixedYcbcr::new(weights);
+                let planes = || convert_planes(buffer, |px| ycbcr.convert(px.rgb()));
                 let alpha = buffer.pixels().map(|px| px.a);
                 self.encode_raw_planes(width, height, planes, Some(alpha))
             }
             10 | 12 => {
-                let planes = buffer
-                    .pixels()
-                    .map(|px| rgb_to_16_bit_ycbcr(px.rgb(), self.bit_depth));
+                let planes = || {
+                    convert_planes(buffer, |px| {
+                        rgb_to_16_bit_ycbcr(px.rgb(), self.bit_depth, weights)
+                    })
+                };
                 let alpha = buffer
                     .pixels()
                     .map(|px| bitshift_16_bit(px.a, self.bit_depth));
@@ -153,71 +552,253 @@ impl Encoder {
     }
 
     fn encode_rgb(&self, in_buffer: Img<&[RGB<u8>]>) -> Result<EncodedImage> {
-        let bitmap = in_buffer.pixels();
         let width = in_buffer.width();
         let height = in_buffer.height();
+        let weights = self.matrix.luma_weights();
 
         match self.bit_depth {
             8 => {
-                let planes = bitmap.map(rgb_to_8_bit_ycbcr);
+                let ycbcr = FixedYcbcr::new(weights);
+                let planes = || convert_planes(in_buffer, |&px| ycbcr.convert(px));
                 self.encode_raw_planes(width, height, planes, None::<[_; 0]>)
             }
 
             10 | 12 => {
-                let planes = bitmap.map(|px| rgb_to_16_bit_ycbcr(px, self.bit_depth));
+                let planes = || {
+                    convert_planes(in_buffer, |&px| {
+                        rgb_to_16_bit_ycbcr(px, self.bit_depth, weights)
+                    })
+                };
                 self.encode_raw_planes(width, height, planes, None::<[_; 0]>)
             }
             _ => unimplemented!(),
         }
     }
 
+    /// Encode 8-bit interleaved pixels, `RGBRGB...` or `RGBARGBA...` when `has_alpha` is set.
+    ///
+    /// This is the entry point for callers with their own pixel buffers, no `DynamicImage`
+    /// needed. The `interleaved_rgba_example` test shows a complete call.
+    ///
+    /// Pixels are full range sRGB, alpha is not premultiplied. If all pixels are opaque,
+    /// the alpha channel is left out automatically.
+    pub fn encode_interleaved(
+        &self,
+        width: usize,
+        height: usize,
+        pixels: &[u8],
+        has_alpha: bool,
+    ) -> Result<EncodedImage> {
+        let channels = if has_alpha { 4 } else { 3 };
+
+        if pixels.len() < width * height * channels {
+            Err(Error::TooFewPixels)?;
+        }
+
+        let pixels = &pixels[..width * height * channels];
+
+        if !has_alpha {
+            return self.encode_rgb(Img::new(pixels.as_rgb(), width, height));
+        }
+
+        let rgba = pixels.as_rgba();
+
+        let start = Instant::now();
+        let transparent = Self::check_transparent_pixel(rgba);
+        trace!("SIMD Eval took {:?}", start.elapsed());
+
+        // Opaque images skip the count, that's the common case
+        let translucent = if transparent {
+            rgba.iter().filter(|px| px.a != 255).count()
+        } else {
+            0
+        };
+
+        if self.keeps_alpha(translucent, rgba.len()) {
+            debug!("Image has transparency, encoding fully.");
+            return self.encode_rgba(Img::new(rgba, width, height));
+        }
+
+        debug!("Image is opaque, discarding alpha channel.");
+        let rgb: Vec<RGB<u8>> = rgba.iter().map(|px| px.rgb()).collect();
+
+        let mut encoded = self.encode_rgb(Img::new(&rgb, width, height))?;
+        encoded.alpha_dropped = translucent > 0;
+
+        Ok(encoded)
+    }
+
+    /// Encode 8-bit grayscale pixels as a single plane AVIF, see [`Encoder::with_monochrome`]
+    pub fn encode_luma(&self, width: usize, height: usize, pixels: &[u8]) -> Result<EncodedImage> {
+        if pixels.len() < width * height {
+            Err(Error::TooFewPixels)?;
+        }
+
+        let pixels = &pixels[..width * height];
+
+        match self.bit_depth {
+            8 => self.encode_single_plane(width, height, pixels.iter().copied()),
+            10 | 12 => self.encode_single_plane(
+                width,
+                height,
+                pixels.iter().map(|&px| bitshift_16_bit(px, self.bit_depth)),
+            ),
+            depth => Err(Error::UnsupportedBitDepth(depth))?,
+        }
+    }
+
+    /// Like [`Encoder::encode_interleaved`] for 16-bit pixels, scaled straight to the
+    /// output depth instead of going through 8 bits first. Only for 10 and 12-bit output.
+    fn encode_interleaved_16(
+        &self,
+        width: usize,
+        height: usize,
+        pixels: &[u16],
+        has_alpha: bool,
+    ) -> Result<EncodedImage> {
+        let channels = if has_alpha { 4 } else { 3 };
+
+        if pixels.len() < width * height * channels {
+            Err(Error::TooFewPixels)?;
+        }
+
+        let pixels = &pixels[..width * height * channels];
+        let (depth, weights) = (self.bit_depth, self.matrix.luma_weights());
+
+        if !has_alpha {
+            let rgb = Img::new(pixels.as_rgb(), width, height);
+            let planes = || convert_planes(rgb, |&px| rgb16_to_ycbcr(px, depth, weights));
+            return self.encode_raw_planes(width, height, planes, None::<[_; 0]>);
+        }
+
+        let rgba = Img::new(pixels.as_rgba(), width, height);
+        let translucent = rgba.pixels().filter(|px| px.a != u16::MAX).count();
+
+        if self.keeps_alpha(translucent, width * height) {
+            debug!("Image has transparency, encoding fully.");
+            let cleaned = blurred_dirty_alpha_16(rgba);
+            let rgba = cleaned.as_ref().map(|img| img.as_ref()).unwrap_or(rgba);
+
+            let planes = || convert_planes(rgba, |px| rgb16_to_ycbcr(px.rgb(), depth, weights));
+            let alpha = rgba.pixels().map(|px| scale_16_bit(px.a, depth));
+            return self.encode_raw_planes(width, height, planes, Some(alpha));
+        }
+
+        debug!("Image is opaque, discarding alpha channel.");
+        let planes = || convert_planes(rgba, |px| rgb16_to_ycbcr(px.rgb(), depth, weights));
+        let mut encoded = self.encode_raw_planes(width, height, planes, None::<[_; 0]>)?;
+        encoded.alpha_dropped = translucent > 0;
+
+        Ok(encoded)
+    }
+
     pub fn encode(&self, image: &mut ImageFile) -> Result<()> {
-        if image.bitmap.color().has_alpha() {
-            let pix_data = image.bitmap.to_rgba8();
+        let (width, height) = (image.width as usize, image.height as usize);
 
-            let start = Instant::now();
-            if Self::check_transparent_pixel(pix_data.as_rgba()) {
-                trace!("SIMD Eval took {:?}", start.elapsed());
+        debug!("Encoding {}", image.original_name());
+
+        let color = image.bitmap.color();
+        // 16-bit and float sources keep their precision when the output has more than 8 bits.
+        // Gamut conversion only works on 8-bit pixels, so it goes through the usual path
+        let high_precision = self.bit_depth > 8
+            && color.bytes_per_pixel() > color.channel_count()
+            && !(self.convert_gamut && self.primaries == Primaries::P3);
+
+        let encoded = if self.monochrome {
+            let luma = image.bitmap.to_luma8().into_raw();
+
+            self.run_with_timeout(move |encoder| encoder.encode_luma(width, height, &luma))
+        } else if high_precision {
+            let has_alpha = color.has_alpha();
+            let pixels = if has_alpha {
+                image.bitmap.to_rgba16().into_raw()
+            } else {
+                image.bitmap.to_rgb16().into_raw()
+            };
+
+            self.run_with_timeout(move |encoder| {
+                encoder.encode_interleaved_16(width, height, &pixels, has_alpha)
+            })
+        } else {
+            let has_alpha = image.bitmap.color().has_alpha();
+            let mut pixels = if has_alpha {
+                image.bitmap.to_rgba8().into_raw()
+            } else {
+                image.bitmap.to_rgb8().into_raw()
+            };
+
+            if self.convert_gamut && self.primaries == Primaries::P3 {
                 debug!(
-                    "Image {} has transparency, encoding fully.",
+                    "Converting {} from sRGB to Display P3",
                     image.original_name()
                 );
+                srgb_to_display_p3(&mut pixels, if has_alpha { 4 } else { 3 });
+            }
 
-                let enc = self.encode_rgba(Img::new(
-                    image.bitmap.to_rgba8().as_rgba(),
-                    image.width as usize,
-                    image.height as usize,
-                ));
+            self.run_with_timeout(move |encoder| {
+                encoder.encode_interleaved(width, height, &pixels, has_alpha)
+            })
+        };
 
-                image.encoded_data = enc?.avif_file;
+        image.set_encoded(encoded?);
 
-                return Ok(());
-            }
-            trace!("SIMD Eval took {:?}", start.elapsed());
-            debug!(
-                "Image {} is opaque, discarding alpha channel.",
-                image.original_name()
-            )
+        Ok(())
+    }
+
+    /// Without a timeout `encode` runs right here. Otherwise it runs on its own thread
+    /// and we stop waiting for it after the timeout, a late result is dropped with the thread.
+    fn run_with_timeout(
+        &self,
+        encode: impl FnOnce(&Encoder) -> Result<EncodedImage> + Send + 'static,
+    ) -> Result<EncodedImage> {
+        let Some(timeout) = self.timeout else {
+            return encode(self);
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let encoder = self.clone();
+
+        thread::spawn(move || {
+            // The receiver is gone if we already timed out
+            let _ = tx.send(encode(&encoder));
+        });
+
+        match rx.recv_timeout(timeout) {
+            Ok(encoded) => encoded,
+            Err(RecvTimeoutError::Timeout) => Err(Error::TimedOut(timeout))?,
+            Err(RecvTimeoutError::Disconnected) => Err(eyre!("Encoder thread exited early")),
         }
+    }
 
-        let raw_map = image.bitmap.to_rgb8();
+    /// Base and minimum quantizer for the color channel
+    fn color_quantizers(&self) -> (u8, u8) {
+        let min = self.min_quantizer.unwrap_or(0);
 
-        let binding = Img::new(
-            raw_map.as_rgb(),
-            image.width as usize,
-            image.height as usize,
-        );
+        if self.bitrate.is_some() {
+            // rav1e treats the base quantizer as the upper bound in bitrate mode
+            return (self.max_quantizer.unwrap_or(self.quantizer).max(min), min);
+        }
 
-        image.encoded_data = self.encode_rgb(binding)?.avif_file;
+        let quantizer = self.quantizer.clamp(min, self.max_quantizer.unwrap_or(255));
+        (quantizer, quantizer)
+    }
 
-        Ok(())
+    /// Whether the alpha channel is encoded, given how many of `total` pixels aren't fully
+    /// opaque. Up to `--prefer-opaque-threshold` of them are treated as opaque
+    fn keeps_alpha(&self, translucent: usize, total: usize) -> bool {
+        translucent > 0 && translucent as f64 / total as f64 > self.opaque_threshold
     }
 
     fn check_transparent_pixel(image: &[RGBA<u8>]) -> bool {
-        // Isolate only the alpha channel.
-        let pixel_alpha = Vec::from_iter(image.iter().map(|pixel| pixel.a));
+        // AND the alpha of fixed-size chunks together without branching, which stable
+        // rustc auto-vectorizes on every target. Only the leftover tail is checked per pixel.
+        let mut chunks = image.chunks_exact(64);
 
-        pixel_alpha.iter().any(|px| px != &255)
+        let opaque = chunks
+            .by_ref()
+            .all(|chunk| chunk.iter().fold(255u8, |acc, pixel| acc & pixel.a) == 255);
+
+        !opaque || chunks.remainder().iter().any(|pixel| pixel.a != 255)
     }
 
     #[inline(never)]
@@ -225,13 +806,13 @@ impl Encoder {
         &self,
         width: usize,
         height: usize,
-        planes: impl IntoIterator<Item = [P; 3]> + Send,
+        planes: impl FnOnce() -> Vec<[P; 3]> + Send,
         alpha: Option<impl IntoIterator<Item = P> + Send>,
     ) -> Result<EncodedImage> {
         let color_description = Some(ColorDescription {
             transfer_characteristics: TransferCharacteristics::SRGB,
-            color_primaries: ColorPrimaries::BT709, // sRGB-compatible
-            matrix_coefficients: MatrixCoefficients::BT601,
+            color_primaries: self.primaries.rav1e(),
+            matrix_coefficients: self.matrix.rav1e(),
         });
 
         let threads = self.threads;
@@ -239,18 +820,29 @@ impl Encoder {
 
         trace!("Encoding color channel");
 
+        let (quantizer, min_quantizer) = self.color_quantizers();
+
         let color = encode_to_av1::<P>(
             &Av1EncodeConfig {
                 width,
                 height,
                 bit_depth: self.bit_depth.into(),
-                quantizer: self.quantizer.into(),
-                speed: SpeedTweaks::from_my_preset(self.speed, self.quantizer),
+                quantizer: quantizer.into(),
+                min_quantizer,
+                bitrate: self
+                    .bitrate
+                    .map_or(0, |kbps| kbps.saturating_mul(1000) as i32),
+                speed: SpeedTweaks::from_my_preset(self.speed, quantizer),
                 threads,
-                chroma_sampling: ChromaSampling::Cs444,
+                chroma_sampling: self.chroma_sampling,
                 color_description,
+                hdr: self.hdr,
+                still_picture: self.still_picture,
+            },
+            move |frame| match self.chroma_sampling {
+                ChromaSampling::Cs444 => init_frame_color(width, height, planes(), frame),
+                sampling => init_frame_color_subsampled(width, height, planes(), frame, sampling),
             },
-            move |frame| init_frame_color(width, height, planes, frame),
         );
 
         let alpha = alpha.map(|alpha| {
@@ -261,10 +853,14 @@ impl Encoder {
                     height,
                     bit_depth: self.bit_depth.into(),
                     quantizer: self.alpha_quantizer.into(),
+                    min_quantizer: self.alpha_quantizer,
+                    bitrate: 0,
                     speed: SpeedTweaks::from_my_preset(self.speed, self.alpha_quantizer),
                     threads,
                     chroma_sampling: ChromaSampling::Cs400,
                     color_description: None,
+                    hdr: HdrMetadata::default(),
+                    still_picture: self.still_picture,
                 },
                 |frame| init_frame_alpha_pix(width, height, alpha, frame),
             )
@@ -272,52 +868,204 @@ impl Encoder {
 
         let (color, alpha) = (color?, alpha.transpose()?);
 
-        let avif_file = avif_serialize::Aviffy::new()
-            .matrix_coefficients(avif_serialize::constants::MatrixCoefficients::Bt601)
+        let timings = Timings {
+            convert: color.convert + alpha.as_ref().map_or(Duration::ZERO, |a| a.convert),
+            color: color.encode,
+            alpha: alpha.as_ref().map_or(Duration::ZERO, |a| a.encode),
+            ..Timings::default()
+        };
+        let (color, alpha) = (color.data, alpha.map(|a| a.data));
+
+        let mut aviffy = avif_serialize::Aviffy::new();
+        aviffy
+            .matrix_coefficients(self.matrix.container())
+            .color_primaries(self.primaries.container())
             .premultiplied_alpha(false)
-            .to_vec(
-                &color,
-                alpha.as_deref(),
-                width as u32,
-                height as u32,
-                self.bit_depth,
-            );
+            .set_seq_profile(seq_profile(self.bit_depth, self.chroma_sampling))
+            .set_chroma_subsampling(match self.chroma_sampling {
+                ChromaSampling::Cs420 => (true, true),
+                ChromaSampling::Cs422 => (true, false),
+                _ => (false, false),
+            });
+        self.hdr.apply_to_container(&mut aviffy);
+
+        let avif_file = aviffy.to_vec(
+            &color,
+            alpha.as_deref(),
+            width as u32,
+            height as u32,
+            self.bit_depth,
+        );
         let color_byte_size = color.len();
         let alpha_byte_size = alpha.as_ref().map_or(0, |a| a.len());
 
         Ok(EncodedImage {
             avif_file,
-            _color_byte_size: color_byte_size,
-            _alpha_byte_size: alpha_byte_size,
+            color_byte_size,
+            alpha_byte_size,
+            alpha_dropped: false,
+            timings,
         })
     }
+
+    #[inline(never)]
+    fn encode_single_plane<P: rav1e::Pixel + Default>(
+        &self,
+        width: usize,
+        height: usize,
+        plane: impl IntoIterator<Item = P> + Send,
+    ) -> Result<EncodedImage> {
+        let color_description = Some(ColorDescription {
+            transfer_characteristics: TransferCharacteristics::SRGB,
+            color_primaries: self.primaries.rav1e(),
+            matrix_coefficients: self.matrix.rav1e(),
+        });
+
+        trace!("Encoding luma channel");
+
+        let (quantizer, min_quantizer) = self.color_quantizers();
+
+        let color = encode_to_av1::<P>(
+            &Av1EncodeConfig {
+                width,
+                height,
+                bit_depth: self.bit_depth.into(),
+                quantizer: quantizer.into(),
+                min_quantizer,
+                bitrate: self
+                    .bitrate
+                    .map_or(0, |kbps| kbps.saturating_mul(1000) as i32),
+                speed: SpeedTweaks::from_my_preset(self.speed, quantizer),
+                threads: self.threads,
+                chroma_sampling: ChromaSampling::Cs400,
+                color_description,
+                hdr: self.hdr,
+                still_picture: self.still_picture,
+            },
+            |frame| init_frame_alpha_pix(width, height, plane, frame),
+        )?;
+
+        let mut aviffy = avif_serialize::Aviffy::new();
+        aviffy
+            .matrix_coefficients(self.matrix.container())
+            .color_primaries(self.primaries.container())
+            .set_seq_profile(seq_profile(self.bit_depth, ChromaSampling::Cs400))
+            .set_monochrome(true);
+        self.hdr.apply_to_container(&mut aviffy);
+
+        let avif_file = aviffy.to_vec(
+            &color.data,
+            None,
+            width as u32,
+            height as u32,
+            self.bit_depth,
+        );
+
+        Ok(EncodedImage {
+            avif_file,
+            color_byte_size: color.data.len(),
+            alpha_byte_size: 0,
+            alpha_dropped: false,
+            timings: Timings {
+                convert: color.convert,
+                color: color.encode,
+                ..Timings::default()
+            },
+        })
+    }
+}
+
+/// Convert every pixel of `buffer` to YCbCr with the rows spread over the rayon pool.
+/// The frame is then filled from the finished planes
+fn convert_planes<T: Sync, P: Send>(
+    buffer: Img<&[T]>,
+    convert: impl Fn(&T) -> [P; 3] + Sync,
+) -> Vec<[P; 3]> {
+    let rows: Vec<&[T]> = buffer.rows().collect();
+
+    rows.into_par_iter()
+        .flat_map_iter(|row| row.iter().map(&convert))
+        .collect()
 }
 
 #[inline(always)]
-fn rgb_to_ycbcr(px: rgb::RGB<u8>, depth: u8) -> (u16, u16, u16) {
-    let matrix = [0.2990, 0.5870, 0.1140]; // BT601
+fn rgb_to_ycbcr(px: rgb::RGB<u8>, depth: u8, matrix: [f32; 3]) -> (u16, u16, u16) {
+    scaled_rgb_to_ycbcr(px.map(f32::from), 255., depth, matrix)
+}
 
+/// `px` channels go from 0 to `input_max`, the output from 0 to the max of `depth`
+#[inline(always)]
+fn scaled_rgb_to_ycbcr(
+    px: rgb::RGB<f32>,
+    input_max: f32,
+    depth: u8,
+    matrix: [f32; 3],
+) -> (u16, u16, u16) {
     let max_value = ((1 << depth) - 1) as f32;
-    let scale = max_value / 255.;
+    let scale = max_value / input_max;
     let shift = (max_value * 0.5).round();
-    let y = scale * matrix[0] * f32::from(px.r)
-        + scale * matrix[1] * f32::from(px.g)
-        + scale * matrix[2] * f32::from(px.b);
-    let cb = (f32::from(px.b) * scale - y).mul_add(0.5 / (1. - matrix[2]), shift);
-    let cr = (f32::from(px.r) * scale - y).mul_add(0.5 / (1. - matrix[0]), shift);
+    let y = scale * matrix[0] * px.r + scale * matrix[1] * px.g + scale * matrix[2] * px.b;
+    let cb = (px.b * scale - y).mul_add(0.5 / (1. - matrix[2]), shift);
+    let cr = (px.r * scale - y).mul_add(0.5 / (1. - matrix[0]), shift);
     (y.round() as u16, cb.round() as u16, cr.round() as u16)
 }
 
 #[inline(always)]
-fn rgb_to_16_bit_ycbcr(px: rgb::RGB<u8>, depth: u8) -> [u16; 3] {
-    let (y, u, v) = rgb_to_ycbcr(px, depth);
+fn rgb16_to_ycbcr(px: rgb::RGB<u16>, depth: u8, matrix: [f32; 3]) -> [u16; 3] {
+    let (y, u, v) = scaled_rgb_to_ycbcr(px.map(f32::from), 65535., depth, matrix);
     [y, u, v]
 }
 
 #[inline(always)]
-fn rgb_to_8_bit_ycbcr(px: rgb::RGB<u8>) -> [u8; 3] {
-    let (y, u, v) = rgb_to_ycbcr(px, 8);
-    [y as u8, u as u8, v as u8]
+fn rgb_to_16_bit_ycbcr(px: rgb::RGB<u8>, depth: u8, matrix: [f32; 3]) -> [u16; 3] {
+    let (y, u, v) = rgb_to_ycbcr(px, depth, matrix);
+    [y, u, v]
+}
+
+/// 8-bit to 8-bit YCbCr in 16.16 fixed point. Within ±1 of [`rgb_to_ycbcr`] for every
+/// color and about 4x faster, which matters in the per-pixel loop of big batches
+#[derive(Clone, Copy)]
+struct FixedYcbcr {
+    y: [i32; 3],
+    cb: [i32; 3],
+    cr: [i32; 3],
+}
+
+impl FixedYcbcr {
+    /// Chroma is offset by 128, plus one half to round
+    const CHROMA_OFFSET: i32 = (128 << 16) + (1 << 15);
+
+    fn new(matrix: [f32; 3]) -> Self {
+        let fixed = |coefficient: f32| (coefficient * 65536.).round() as i32;
+        let [kr, kg, kb] = matrix;
+        let (cb_scale, cr_scale) = (0.5 / (1. - kb), 0.5 / (1. - kr));
+
+        Self {
+            y: [fixed(kr), fixed(kg), fixed(kb)],
+            cb: [
+                fixed(-kr * cb_scale),
+                fixed(-kg * cb_scale),
+                fixed((1. - kb) * cb_scale),
+            ],
+            cr: [
+                fixed((1. - kr) * cr_scale),
+                fixed(-kg * cr_scale),
+                fixed(-kb * cr_scale),
+            ],
+        }
+    }
+
+    #[inline(always)]
+    fn convert(&self, px: rgb::RGB<u8>) -> [u8; 3] {
+        let (r, g, b) = (i32::from(px.r), i32::from(px.g), i32::from(px.b));
+        let dot = |c: [i32; 3]| c[0] * r + c[1] * g + c[2] * b;
+
+        let y = (dot(self.y) + (1 << 15)) >> 16;
+        let cb = (dot(self.cb) + Self::CHROMA_OFFSET) >> 16;
+        let cr = (dot(self.cr) + Self::CHROMA_OFFSET) >> 16;
+
+        [y, cb, cr].map(|v| v.clamp(0, 255) as u8)
+    }
 }
 
 fn quality_to_quantizer(quality: f32) -> u8 {
@@ -332,9 +1080,28 @@ fn quality_to_quantizer(quality: f32) -> u8 {
     (x * 255.).round() as u8
 }
 
+/// AV1 profile for the av1C box: 12-bit and 4:2:2 need Professional,
+/// 4:4:4 needs High and everything else fits Main
+fn seq_profile(bit_depth: u8, sampling: ChromaSampling) -> u8 {
+    match sampling {
+        _ if bit_depth == 12 => 2,
+        ChromaSampling::Cs422 => 2,
+        ChromaSampling::Cs444 => 1,
+        _ => 0,
+    }
+}
+
+/// Rescale a 16-bit value to `depth` bits, rounding to nearest
+fn scale_16_bit(x: u16, depth: u8) -> u16 {
+    let max_value = (1u32 << depth) - 1;
+    ((u32::from(x) * max_value + 32767) / 65535) as u16
+}
+
+/// Widen an 8-bit value to `mag` bits by repeating its top bits in the new low bits,
+/// so 255 maps to the new max
 fn bitshift_16_bit(x: u8, mag: u8) -> u16 {
     let lhs = if mag == 10 { 2 } else { 4 };
-    let rhs = if mag == 10 { 6 } else { 12 };
+    let rhs = 8 - lhs;
 
     ((x as u16) << lhs) | ((x as u16) >> rhs)
 }
@@ -489,27 +1256,37 @@ impl SpeedTweaks {
     }
 }
 
+/// Log target of the settings printed by `--encoder-log`
+pub const ENCODER_LOG_TARGET: &str = "rav1e_config";
+
 struct Av1EncodeConfig {
     pub width: usize,
     pub height: usize,
     pub bit_depth: usize,
     pub quantizer: usize,
+    /// Lower bound for rate control, the same as `quantizer` in constant quantizer mode
+    pub min_quantizer: u8,
+    /// Target bits per second. 0 means constant quantizer mode
+    pub bitrate: i32,
     pub speed: SpeedTweaks,
     /// 0 means num_cpus
     pub threads: usize,
     pub chroma_sampling: ChromaSampling,
     pub color_description: Option<ColorDescription>,
+    pub hdr: HdrMetadata,
+    pub still_picture: bool,
 }
 
 fn rav1e_config(p: &Av1EncodeConfig) -> Config {
     // AV1 needs all the CPU power you can give it,
-    // except when it'd create inefficiently tiny tiles
+    // except when it'd create inefficiently tiny tiles.
+    // Batches can lower `threads` further with `--max-threads-per-image`
     let tiles = {
         let threads = p.threads;
         threads.min((p.width * p.height) / (p.speed.min_tile_size as usize).pow(2))
     };
     let speed_settings = p.speed.speed_settings();
-    let cfg = Config::new().with_encoder_config(EncoderConfig {
+    let config = EncoderConfig {
         width: p.width,
         height: p.height,
         time_base: Rational::new(1, 1),
@@ -519,19 +1296,20 @@ fn rav1e_config(p: &Av1EncodeConfig) -> Config {
         chroma_sample_position: ChromaSamplePosition::Unknown,
         pixel_range: PixelRange::Full,
         color_description: p.color_description,
-        mastering_display: None,
-        content_light: None,
+        mastering_display: p.hdr.rav1e_mastering_display(),
+        content_light: p.hdr.rav1e_content_light(),
         enable_timing_info: false,
-        still_picture: true,
+        still_picture: p.still_picture,
         error_resilient: false,
         switch_frame_interval: 0,
         min_key_frame_interval: 0,
         max_key_frame_interval: 0,
         reservoir_frame_delay: None,
         low_latency: false,
+        // In bitrate mode rate control picks a quantizer between the two
         quantizer: p.quantizer,
-        min_quantizer: p.quantizer as _,
-        bitrate: 0,
+        min_quantizer: p.min_quantizer,
+        bitrate: p.bitrate,
         tune: Tune::Psychovisual,
         tile_cols: 0,
         tile_rows: 0,
@@ -539,8 +1317,22 @@ fn rav1e_config(p: &Av1EncodeConfig) -> Config {
         film_grain_params: None,
         level_idx: None,
         speed_settings,
-    });
-    cfg.with_threads(p.threads)
+    };
+
+    info!(
+        target: ENCODER_LOG_TARGET,
+        "{}x{} {}-bit {:?}, speed {}, {tiles} tiles, {} threads: {config}",
+        p.width,
+        p.height,
+        p.bit_depth,
+        p.chroma_sampling,
+        p.speed.speed_preset,
+        p.threads
+    );
+
+    Config::new()
+        .with_encoder_config(config)
+        .with_threads(p.threads)
 }
 
 fn init_frame_color<P: rav1e::Pixel + Default>(
@@ -576,6 +1368,59 @@ fn init_frame_color<P: rav1e::Pixel + Default>(
     Ok(())
 }
 
+/// Like `init_frame_color`, but each chroma sample is the average of a 2x1 (4:2:2)
+/// or 2x2 (4:2:0) block. Odd widths and heights repeat the last column and row.
+fn init_frame_color_subsampled<P: rav1e::Pixel + Default>(
+    width: usize,
+    height: usize,
+    planes: impl IntoIterator<Item = [P; 3]> + Send,
+    frame: &mut Frame<P>,
+    sampling: ChromaSampling,
+) -> Result<()> {
+    let pixels: Vec<[P; 3]> = planes.into_iter().take(width * height).collect();
+
+    if pixels.len() < width * height {
+        Err(Error::TooFewPixels)?;
+    }
+
+    let mut f = frame.planes.iter_mut();
+
+    let mut y = f.next().unwrap().mut_slice(Default::default());
+    for (row, y) in y.rows_iter_mut().take(height).enumerate() {
+        for (x, y) in y[..width].iter_mut().enumerate() {
+            *y = pixels[row * width + x][0];
+        }
+    }
+
+    let (xdec, ydec) = sampling.get_decimation().unwrap_or((0, 0));
+    let (chroma_width, chroma_height) = sampling.get_chroma_dimensions(width, height);
+    let block: Vec<(usize, usize)> = (0..=ydec)
+        .flat_map(|dy| (0..=xdec).map(move |dx| (dy, dx)))
+        .collect();
+    let count = block.len() as u32;
+
+    for (channel, plane) in f.take(2).enumerate() {
+        let mut plane = plane.mut_slice(Default::default());
+
+        for (cy, row) in plane.rows_iter_mut().take(chroma_height).enumerate() {
+            for (cx, out) in row[..chroma_width].iter_mut().enumerate() {
+                let sum: u32 = block
+                    .iter()
+                    .map(|&(dy, dx)| {
+                        let y = ((cy << ydec) + dy).min(height - 1);
+                        let x = ((cx << xdec) + dx).min(width - 1);
+                        u32::cast_from(pixels[y * width + x][channel + 1])
+                    })
+                    .sum();
+
+                *out = P::cast_from((sum + count / 2) / count);
+            }
+        }
+    }
+
+    Ok(())
+}
+
 fn init_frame_alpha_pix<P: rav1e::Pixel + Default>(
     width: usize,
     height: usize,
@@ -594,30 +1439,265 @@ fn init_frame_alpha_pix<P: rav1e::Pixel + Default>(
     Ok(())
 }
 
+/// AV1 data of one channel and how long it took
+struct Av1Payload {
+    data: Vec<u8>,
+    /// Filling the frame, which is where pixels get converted
+    convert: Duration,
+    encode: Duration,
+}
+
 #[inline(never)]
 fn encode_to_av1<P: rav1e::Pixel>(
     p: &Av1EncodeConfig,
     init: impl FnOnce(&mut Frame<P>) -> Result<()>,
-) -> Result<Vec<u8>> {
-    let mut ctx: Context<P> = rav1e_config(p).new_context()?;
+) -> Result<Av1Payload> {
+    let mut ctx: Context<P> = rav1e_config(p).new_context().map_err(Error::from)?;
     let mut frame = ctx.new_frame();
 
+    let start = Instant::now();
     init(&mut frame)?;
-    ctx.send_frame(frame)?;
+    let convert = start.elapsed();
+
+    let start = Instant::now();
+    ctx.send_frame(frame).map_err(Error::from)?;
     ctx.flush();
 
+    Ok(Av1Payload {
+        data: receive_av1(&mut ctx)?,
+        convert,
+        encode: start.elapsed(),
+    })
+}
+
+/// Collect every packet of a flushed context
+fn receive_av1<P: rav1e::Pixel>(ctx: &mut Context<P>) -> Result<Vec<u8>> {
     let mut out = Vec::new();
     loop {
         match ctx.receive_packet() {
-            Ok(mut packet) => match packet.frame_type {
-                FrameType::KEY => {
-                    out.append(&mut packet.data);
-                }
-                _ => continue,
-            },
+            // A single frame is always a key frame, anything else would be a delta frame
+            // of a sequence and is kept as well
+            Ok(mut packet) => {
+                trace!("Received {:?} packet", packet.frame_type);
+                out.append(&mut packet.data);
+            }
             Err(EncoderStatus::Encoded) | Err(EncoderStatus::LimitReached) => break,
-            Err(err) => Err(err)?,
+            Err(err) => Err(Error::from(err))?,
         }
     }
+
+    // Muxing an empty payload would give a file nothing can open
+    if out.is_empty() {
+        Err(Error::EmptyOutput)?;
+    }
+
     Ok(out)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Payloads of every `name` box, boxes are found by scanning for their type
+    fn find_boxes<'a>(avif: &'a [u8], name: &'a [u8; 4]) -> impl Iterator<Item = &'a [u8]> {
+        (8..avif.len())
+            .filter(move |&start| &avif[start - 4..start] == name)
+            .map(move |start| {
+                let size = u32::from_be_bytes(avif[start - 8..start - 4].try_into().unwrap());
+                &avif[start..start - 8 + size as usize]
+            })
+    }
+
+    fn find_box<'a>(avif: &'a [u8], name: &'a [u8; 4]) -> &'a [u8] {
+        find_boxes(avif, name).next().expect("box is missing")
+    }
+
+    /// Width and height from the `ispe` box
+    fn dimensions(avif: &[u8]) -> (u32, u32) {
+        let ispe = find_box(avif, b"ispe");
+
+        (
+            u32::from_be_bytes(ispe[4..8].try_into().unwrap()),
+            u32::from_be_bytes(ispe[8..12].try_into().unwrap()),
+        )
+    }
+
+    fn encode(pixels: &[u8], sampling: Subsampling, bit_depth: u8) -> Vec<u8> {
+        Encoder::new()
+            .with_speed(10)
+            .with_num_threads(1)
+            .with_bit_depth(bit_depth)
+            .with_chroma_subsampling(sampling)
+            .encode_interleaved(64, 48, pixels, false)
+            .unwrap()
+            .avif_file
+    }
+
+    #[test]
+    fn interleaved_rgba_example() -> Result<()> {
+        let (width, height) = (64, 48);
+        let pixels: Vec<u8> = (0..width * height)
+            .flat_map(|i| [(i % width) as u8, (i / width) as u8, 128, (i % 256) as u8])
+            .collect();
+
+        let encoded = Encoder::new()
+            .with_quality(70.)
+            .with_speed(10)
+            .encode_interleaved(width, height, &pixels, true)?;
+
+        assert_eq!(dimensions(&encoded.avif_file), (64, 48));
+        assert!(encoded.alpha_byte_size > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_frames_is_an_error() {
+        let config = Av1EncodeConfig {
+            width: 64,
+            height: 48,
+            bit_depth: 8,
+            quantizer: 100,
+            min_quantizer: 100,
+            bitrate: 0,
+            speed: SpeedTweaks::from_my_preset(10, 100),
+            threads: 1,
+            chroma_sampling: ChromaSampling::Cs444,
+            color_description: None,
+            hdr: HdrMetadata::default(),
+            still_picture: true,
+        };
+        let mut ctx: Context<u8> = rav1e_config(&config).new_context().unwrap();
+        ctx.flush();
+
+        let err = receive_av1(&mut ctx).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::EmptyOutput)));
+    }
+
+    #[test]
+    fn subsampled_encode_is_smaller() {
+        // A solid color has no chroma detail to drop, stripes in every channel do
+        let pixels: Vec<u8> = (0..64 * 48)
+            .flat_map(|i| [(i % 64 * 4) as u8, (i / 64 * 5) as u8, (i % 7 * 36) as u8])
+            .collect();
+
+        let full = encode(&pixels, Subsampling::Cs444, 10);
+        let subsampled = encode(&pixels, Subsampling::Cs420, 10);
+
+        assert!(subsampled.len() < full.len());
+        assert_eq!(dimensions(&subsampled), (64, 48));
+    }
+
+    #[test]
+    fn av1c_signals_profile_and_subsampling() {
+        for (sampling, bit_depth, profile, flags) in [
+            (Subsampling::Cs444, 8, 1, 0x00),
+            (Subsampling::Cs422, 8, 2, 0x08),
+            (Subsampling::Cs420, 8, 0, 0x0c),
+            (Subsampling::Cs420, 10, 0, 0x4c),
+            (Subsampling::Cs420, 12, 2, 0x6c),
+        ] {
+            let avif = encode(&[200, 40, 90].repeat(64 * 48), sampling, bit_depth);
+            let av1c = find_box(&avif, b"av1C");
+
+            assert_eq!(av1c[1] >> 5, profile, "{sampling:?} at {bit_depth} bits");
+            assert_eq!(av1c[2] & 0x6c, flags, "{sampling:?} at {bit_depth} bits");
+        }
+    }
+
+    #[test]
+    fn alpha_widens_to_full_range() {
+        assert_eq!(bitshift_16_bit(0, 10), 0);
+        assert_eq!(bitshift_16_bit(255, 10), 1023);
+        assert_eq!(bitshift_16_bit(0, 12), 0);
+        assert_eq!(bitshift_16_bit(255, 12), 4095);
+        assert_eq!(bitshift_16_bit(128, 12), 0x808);
+    }
+
+    #[test]
+    fn container_depth_matches_bit_depth() {
+        // A horizontal alpha ramp, so the alpha item is kept
+        let pixels: Vec<u8> = (0..64 * 48)
+            .flat_map(|i| [90, 160, 30, (i % 64 * 4) as u8])
+            .collect();
+
+        for bit_depth in [8, 10, 12] {
+            let avif = Encoder::new()
+                .with_speed(10)
+                .with_num_threads(1)
+                .with_bit_depth(bit_depth)
+                .encode_interleaved(64, 48, &pixels, true)
+                .unwrap()
+                .avif_file;
+
+            // Color and alpha each have their own pixi, one depth byte per channel
+            let depths: Vec<&[u8]> = find_boxes(&avif, b"pixi").map(|pixi| &pixi[5..]).collect();
+            assert_eq!(depths, [&[bit_depth; 3][..], &[bit_depth]]);
+
+            for av1c in find_boxes(&avif, b"av1C") {
+                assert_eq!(av1c[2] & 0x40 != 0, bit_depth >= 10);
+                assert_eq!(av1c[2] & 0x20 != 0, bit_depth == 12);
+            }
+        }
+    }
+
+    #[test]
+    fn rgb16_keeps_precision() {
+        let weights = Matrix::Bt709.luma_weights();
+        let gray = |value| rgb16_to_ycbcr(RGB::new(value, value, value), 12, weights)[0];
+
+        assert_eq!(gray(0), 0);
+        assert_eq!(gray(u16::MAX), 4095);
+        // 1/4096 apart, the same value once cut down to 8 bits
+        assert_ne!(gray(32_768), gray(32_784));
+    }
+
+    #[test]
+    fn fixed_point_matches_float_conversion() {
+        for matrix in [Matrix::Bt601, Matrix::Bt709, Matrix::Bt2020ncl] {
+            let weights = matrix.luma_weights();
+            let fixed = FixedYcbcr::new(weights);
+
+            for r in (0..=255).step_by(3) {
+                for g in (0..=255).step_by(3) {
+                    for b in (0..=255).step_by(3) {
+                        let px = RGB::new(r, g, b);
+                        let (y, cb, cr) = rgb_to_ycbcr(px, 8, weights);
+
+                        for (fixed, float) in fixed.convert(px).into_iter().zip([y, cb, cr]) {
+                            assert!(
+                                i32::from(fixed).abs_diff(i32::from(float)) <= 1,
+                                "{px:?} with {matrix:?}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// `cargo test --release -- --ignored --nocapture bench_ycbcr`
+    #[test]
+    #[ignore]
+    fn bench_ycbcr_conversion() {
+        let pixels: Vec<RGB<u8>> = (0..50_000_000u32)
+            .map(|i| RGB::new(i as u8, (i >> 8) as u8, (i >> 16) as u8))
+            .collect();
+        let weights = Matrix::Bt709.luma_weights();
+        let fixed = FixedYcbcr::new(weights);
+
+        let start = Instant::now();
+        let sum: u64 = pixels
+            .iter()
+            .map(|&px| u64::from(rgb_to_ycbcr(px, 8, weights).0))
+            .sum();
+        println!("float: {:?} ({sum})", start.elapsed());
+
+        let start = Instant::now();
+        let sum: u64 = pixels
+            .iter()
+            .map(|&px| u64::from(fixed.convert(px)[0]))
+            .sum();
+        println!("fixed: {:?} ({sum})", start.elapsed());
+    }
+}
@@ -1,3 +1,5 @@
+use std::{path::PathBuf, time::Duration};
+
 use thiserror::Error;
 
 #[derive(Debug)]
@@ -10,4 +12,41 @@ pub enum Error {
     /// Slices given to `encode_raw_planes` must be `width * height` large.
     #[error("Provided buffer is smaller than width * height")]
     TooFewPixels,
+    /// rav1e finished without producing a key frame, there is nothing to put in the container.
+    #[error("Encoder produced no key frame data")]
+    EmptyOutput,
+    /// The file extension isn't one of the supported input formats.
+    #[error("Unsupported image format: {0:?}")]
+    UnsupportedFormat(PathBuf),
+    /// The input couldn't be decoded, `reason` comes from the decoder.
+    #[error("Failed to decode {path:?}: {reason}")]
+    Decode { path: PathBuf, reason: String },
+    /// The decoded image is empty or narrower than the encoder accepts.
+    #[error("Image {path:?} is too small to encode ({width}x{height})")]
+    TooSmall {
+        path: PathBuf,
+        width: u32,
+        height: u32,
+    },
+    /// The encode ran past the `--timeout-per-file` budget, whatever it produced is discarded.
+    #[error("Encode took longer than {0:?}")]
+    TimedOut(Duration),
+    /// The `--crop` rectangle doesn't fit in the image.
+    #[error("Crop doesn't fit in {path:?} ({width}x{height})")]
+    CropOutOfBounds {
+        path: PathBuf,
+        width: u32,
+        height: u32,
+    },
+    /// Only 8, 10 and 12-bit output exists.
+    #[error("Unsupported bit depth: {0}")]
+    UnsupportedBitDepth(u8),
+    /// rav1e rejected the encoder configuration.
+    #[error("Invalid encoder config: {0}")]
+    InvalidConfig(#[from] rav1e::InvalidConfig),
+    /// rav1e failed while encoding.
+    #[error("Encoder error: {0}")]
+    Encoder(#[from] rav1e::EncoderStatus),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
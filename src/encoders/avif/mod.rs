@@ -1,3 +1,3 @@
 mod alpha;
 pub mod encode;
-mod error;
+pub mod error;
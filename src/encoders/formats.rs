@@ -0,0 +1,30 @@
+use color_eyre::{eyre::eyre, Result};
+use image::{codecs::jpeg::JpegEncoder, DynamicImage};
+use webp::Encoder;
+
+/// Re-encode as baseline JPEG. Alpha is dropped since JPEG can't carry it.
+pub fn encode_jpeg(bitmap: &DynamicImage, quality: u8) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+
+    JpegEncoder::new_with_quality(&mut data, quality.clamp(1, 100))
+        .encode_image(&DynamicImage::ImageRgb8(bitmap.to_rgb8()))?;
+
+    Ok(data)
+}
+
+/// Encode as lossy WebP at `quality`, on the same 1-100 scale as the AVIF and JPEG encodes.
+pub fn encode_webp(bitmap: &DynamicImage, quality: u8) -> Result<Vec<u8>> {
+    let (width, height) = (bitmap.width(), bitmap.height());
+    let quality = f32::from(quality.clamp(1, 100));
+
+    let data = if bitmap.color().has_alpha() {
+        let pixels = bitmap.to_rgba8();
+        Encoder::from_rgba(&pixels, width, height).encode_simple(false, quality)
+    } else {
+        let pixels = bitmap.to_rgb8();
+        Encoder::from_rgb(&pixels, width, height).encode_simple(false, quality)
+    }
+    .map_err(|err| eyre!("WebP encoding failed: {err:?}"))?;
+
+    Ok(data.to_vec())
+}
@@ -1 +1,2 @@
 pub mod avif;
+pub mod formats;
@@ -0,0 +1,170 @@
+use std::str::FromStr;
+
+use image::DynamicImage;
+
+/// Pre-processing step applied to the bitmap before it gets encoded
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Filter {
+    Grayscale,
+    Invert,
+    /// Gaussian blur with the given sigma
+    Blur(f32),
+    /// Unsharp mask with the given sigma
+    Sharpen(f32),
+    /// Multiply every color channel by the given factor
+    Brightness(f32),
+    /// Contrast adjustment. Positive values increase contrast, negative ones decrease it
+    Contrast(f32),
+    /// Clockwise rotation
+    Rotate(Rotation),
+    Flip(Flip),
+}
+
+/// Clockwise angle for [`Filter::Rotate`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rotation {
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "90" => Ok(Rotation::Deg90),
+            "180" => Ok(Rotation::Deg180),
+            "270" => Ok(Rotation::Deg270),
+            _ => Err("rotation must be either 90, 180 or 270".to_string()),
+        }
+    }
+}
+
+/// Mirror axis for [`Filter::Flip`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum Flip {
+    /// Left to right
+    H,
+    /// Top to bottom
+    V,
+}
+
+impl Filter {
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        match *self {
+            Filter::Grayscale => image.grayscale(),
+            Filter::Invert => {
+                let mut image = image.clone();
+                image.invert();
+                image
+            }
+            Filter::Blur(sigma) => image.blur(sigma),
+            Filter::Sharpen(sigma) => image.unsharpen(sigma, 1),
+            Filter::Brightness(factor) => {
+                let mut buffer = image.to_rgba8();
+
+                for pixel in buffer.pixels_mut() {
+                    for channel in &mut pixel.0[..3] {
+                        *channel = (f32::from(*channel) * factor).round().clamp(0., 255.) as u8;
+                    }
+                }
+
+                DynamicImage::ImageRgba8(buffer)
+            }
+            Filter::Contrast(value) => image.adjust_contrast(value),
+            Filter::Rotate(Rotation::Deg90) => image.rotate90(),
+            Filter::Rotate(Rotation::Deg180) => image.rotate180(),
+            Filter::Rotate(Rotation::Deg270) => image.rotate270(),
+            Filter::Flip(Flip::H) => image.fliph(),
+            Filter::Flip(Flip::V) => image.flipv(),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    /// Parses `name` or `name=value`, e.g. `grayscale` or `blur=2.0`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (s, None),
+        };
+
+        let value = |default: Option<f32>| -> Result<f32, String> {
+            match value {
+                Some(v) => v
+                    .parse()
+                    .map_err(|_| format!("`{v}` isn't a valid number for filter `{name}`")),
+                None => default.ok_or_else(|| format!("filter `{name}` requires a value")),
+            }
+        };
+
+        match name.to_lowercase().as_str() {
+            "grayscale" => Ok(Filter::Grayscale),
+            "invert" => Ok(Filter::Invert),
+            "blur" => Ok(Filter::Blur(value(None)?)),
+            "sharpen" => Ok(Filter::Sharpen(value(Some(1.0))?)),
+            "brightness" => Ok(Filter::Brightness(value(None)?)),
+            "contrast" => Ok(Filter::Contrast(value(None)?)),
+            _ => Err(format!(
+                "unknown filter `{name}`. Available: grayscale, invert, blur=SIGMA, sharpen[=SIGMA], brightness=FACTOR, contrast=VALUE"
+            )),
+        }
+    }
+}
+
+/// Rectangle kept by `--crop`, in source pixels
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Crop {
+    /// Whether the rectangle fits inside a `width`x`height` image
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        self.x
+            .checked_add(self.width)
+            .is_some_and(|right| right <= width)
+            && self
+                .y
+                .checked_add(self.height)
+                .is_some_and(|bottom| bottom <= height)
+    }
+
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        image.crop_imm(self.x, self.y, self.width, self.height)
+    }
+}
+
+impl FromStr for Crop {
+    type Err = String;
+
+    /// Parses `x,y,width,height`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<u32> = s
+            .split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("`{s}` isn't a valid X,Y,WIDTH,HEIGHT rectangle"))?;
+
+        let [x, y, width, height] = values[..] else {
+            return Err(format!("`{s}` isn't a valid X,Y,WIDTH,HEIGHT rectangle"));
+        };
+
+        if width < 32 || height == 0 {
+            return Err("crop must be at least 32 pixels wide and 1 pixel high".to_string());
+        }
+
+        Ok(Crop {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
@@ -1,15 +1,36 @@
-use crate::encoders::avif::encode::Encoder;
+use crate::{
+    decoders::{self, icc::ProfileWarning},
+    encoders::avif::encode::{
+        EncodedImage, Encoder, HdrMetadata, Matrix, Primaries, Subsampling, Timings,
+    },
+    encoders::avif::error::Error,
+};
 use color_eyre::eyre::{bail, Result};
-use image::{imageops::overlay, io::Reader, DynamicImage, ImageBuffer, ImageFormat};
+use image::{
+    imageops::{overlay, replace, FilterType},
+    io::Reader,
+    DynamicImage, ExtendedColorType, ImageBuffer, ImageDecoder, ImageFormat, RgbImage,
+};
 use indicatif::ProgressBar;
-use log::debug;
+use log::{debug, error};
 use std::{
     fs::{self, OpenOptions},
-    io::{Seek, Write},
+    io::{ErrorKind, Seek, Write},
     path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::DirConfig,
+    filter::{Crop, Filter},
+    name_fun::Name,
+    utils::retry_io,
 };
 
-use crate::name_fun::Name;
+/// Number of files staged in `--tmpdir` so far, keeps the temporary names apart
+static STAGED_FILES: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Debug, Clone)]
 pub struct FileMetadata {
@@ -20,109 +41,519 @@ pub struct FileMetadata {
     pub size: u64,
 }
 
+/// Input extensions accepted by [`ImageFile::new_from_path`], camera raw ones aside
+pub const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "jfif", "png", "webp", "bmp", "tif", "tiff", "avif",
+];
+
+/// Whether files with this extension, in any case, can be converted in this build
+pub fn is_supported(ext: &str) -> bool {
+    #[cfg(feature = "raw")]
+    if decoders::raw::is_raw_extension(ext) {
+        return true;
+    }
+
+    SUPPORTED_EXTENSIONS
+        .iter()
+        .any(|supported| supported.eq_ignore_ascii_case(ext))
+}
+
+/// Everything that controls how an [`ImageFile`] gets encoded
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    pub quality: u8,
+    pub speed: u8,
+    pub threads: usize,
+    pub bit_depth: u8,
+    /// Composite transparent pixels onto this color and encode without alpha
+    pub background: Option<[u8; 3]>,
+    /// Encode the alpha channel at quality 100 whatever `quality` is
+    pub lossless_alpha: bool,
+    /// Cut out of the decoded image before the filters run
+    pub crop: Option<Crop>,
+    /// Applied in order right after decoding
+    pub filters: Vec<Filter>,
+    /// Target bitrate in kbps, overrides `quality` when set
+    pub bitrate: Option<u32>,
+    /// Color quantizer bounds, see [`Encoder::with_quantizer_range`]
+    pub min_quantizer: Option<u8>,
+    pub max_quantizer: Option<u8>,
+    pub hdr: HdrMetadata,
+    pub matrix: Matrix,
+    pub primaries: Primaries,
+    /// Convert sRGB sources to `primaries` instead of only tagging them
+    pub convert_gamut: bool,
+    /// Convert sources with an ICC profile to sRGB
+    pub convert_to_srgb: bool,
+    /// Retries of the decode after transient IO errors
+    pub io_retries: u32,
+    /// Single plane encode, color and alpha are dropped
+    pub monochrome: bool,
+    /// Chroma subsampling of the color channel
+    pub subsampling: Subsampling,
+    /// Encode as an AV1 still picture, `--no-still-picture` turns it off
+    pub still_picture: bool,
+    /// Encode without alpha when at most this fraction of pixels isn't opaque
+    pub opaque_threshold: f64,
+    /// Downscale to this width before encoding, keeping the aspect ratio
+    pub width: Option<u32>,
+    /// Abandon encodes running longer than this
+    pub timeout: Option<Duration>,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            quality: 70,
+            speed: 4,
+            threads: 1,
+            bit_depth: 10,
+            background: None,
+            lossless_alpha: false,
+            crop: None,
+            filters: Vec::new(),
+            bitrate: None,
+            min_quantizer: None,
+            max_quantizer: None,
+            hdr: HdrMetadata::default(),
+            matrix: Matrix::default(),
+            primaries: Primaries::default(),
+            convert_gamut: false,
+            convert_to_srgb: false,
+            io_retries: 0,
+            monochrome: false,
+            subsampling: Subsampling::Cs444,
+            still_picture: true,
+            opaque_threshold: 0.,
+            width: None,
+            timeout: None,
+        }
+    }
+}
+
+/// Apply the mode, and on Unix the owner and group, of `source` to `output`.
+/// Changing ownership usually needs privileges, so a refusal is only logged.
+fn copy_permissions(source: &fs::Metadata, output: &Path) -> Result<()> {
+    fs::set_permissions(output, source.permissions())?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        if let Err(err) = std::os::unix::fs::chown(output, Some(source.uid()), Some(source.gid())) {
+            debug!("Couldn't change the owner of {output:?}: {err}");
+        }
+    }
+
+    Ok(())
+}
+
+/// One of several outputs encoded from the same source
+#[derive(Debug, Clone)]
+pub struct Variant {
+    /// Appended to the output name, e.g. `_q40`
+    pub suffix: Option<String>,
+    pub options: EncodeOptions,
+}
+
+/// Everything that controls how and where an encoded [`ImageFile`] is written
+#[derive(Debug, Clone, Default)]
+pub struct SaveOptions {
+    pub name: Name,
+    /// Appended to `Name::Same` names, e.g. `photo_compressed.avif`
+    pub name_suffix: Option<String>,
+    pub keep: bool,
+    /// Re-read the written file and restore the original if it doesn't match
+    pub verify: bool,
+    /// Save as `photo.jpg.avif` instead of `photo.avif`
+    pub keep_extension: bool,
+    /// Use `.AVIF` for sources with an upper case extension
+    pub match_extension_case: bool,
+    /// Skip writing when a hash-named output with identical bytes already exists
+    pub dedup: bool,
+    /// Stage the output here and move it into place once fully written
+    pub tmpdir: Option<PathBuf>,
+    /// Copy the source's permissions, and ownership where allowed, to the output
+    pub preserve_perms: bool,
+    /// Save the source as `<file>.orig` before it gets replaced
+    pub backup: bool,
+    /// Retries of the output write after transient IO errors
+    pub io_retries: u32,
+}
+
+/// Result of [`ImageFile::save_avif`]
+#[derive(Debug, Clone)]
+pub struct SavedFile {
+    pub path: PathBuf,
+    /// An identical file already existed, nothing was written
+    pub duplicate: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct ImageFile {
     pub metadata: FileMetadata,
     pub format: ImageFormat,
     pub bitmap: DynamicImage,
     pub encoded_data: Vec<u8>,
+    /// Bytes of AV1 payload used for the color channels
+    pub color_byte_size: usize,
+    /// Bytes of AV1 payload used for the alpha channel, 0 for opaque outputs
+    pub alpha_byte_size: usize,
+    /// Alpha was left out because the image was nearly opaque, see `--prefer-opaque-threshold`
+    pub alpha_dropped: bool,
+    /// Time spent decoding and encoding the last output
+    pub timings: Timings,
+    /// Width and height of the last output
+    pub encoded_dimensions: (u32, u32),
     pub height: u32,
     pub width: u32,
+    /// Zero-based page index when this file is one page of a multi-page TIFF
+    pub page: Option<usize>,
+    /// Settings from the nearest `.avifconvert.toml`, if any
+    pub dir_config: Option<DirConfig>,
+    /// Appended to the output name when this source produces several outputs
+    pub suffix: Option<String>,
+    /// Decode as this format instead of going by the extension, see `--input-format`
+    pub input_format: Option<ImageFormat>,
+    /// ICC profile embedded in the source, only read for formats decoded by `image`
+    pub icc_profile: Option<Vec<u8>>,
+    /// The ICC profile isn't sRGB, but the pixels were decoded as if it were
+    pub profile_warning: Option<ProfileWarning>,
+    /// Color type the source was stored in, before the decoder converted it
+    source_color: Option<ExtendedColorType>,
+    /// Last `--widths` downscale. Outputs of the same width reuse it and narrower ones
+    /// resize from it instead of the full bitmap. Only one is kept, so the extra memory
+    /// is at most one bitmap smaller than the source
+    downscaled: Option<DynamicImage>,
 }
 
 impl ImageFile {
     pub fn new_from_path(path: &Path) -> Result<Self> {
-        if let Some(ext) = path.extension() {
-            let ext = ext.to_string_lossy().to_lowercase();
-            if !(ext == "jpg"
-                || ext == "png"
-                || ext == "jpeg"
-                || ext == "jfif"
-                || ext == "webp"
-                || ext == "bmp"
-                || ext == "avif")
-            {
-                bail!("Unsupported image format");
-            }
-        } else {
-            bail!("Invalid file extension");
+        if !path
+            .extension()
+            .is_some_and(|ext| is_supported(&ext.to_string_lossy()))
+        {
+            Err(Error::UnsupportedFormat(path.to_path_buf()))?;
         }
 
+        Self::from_path_unchecked(path)
+    }
+
+    /// With an `input_format`, any extension (or none) is accepted and the file
+    /// is decoded as that format. Without, the same as [`ImageFile::new_from_path`]
+    pub fn open(path: &Path, input_format: Option<ImageFormat>) -> Result<Self> {
+        let Some(format) = input_format else {
+            return Self::new_from_path(path);
+        };
+
+        Ok(Self {
+            input_format: Some(format),
+            ..Self::from_path_unchecked(path)?
+        })
+    }
+
+    fn from_path_unchecked(path: &Path) -> Result<Self> {
         Ok(Self {
             metadata: FileMetadata {
                 path: path.to_path_buf(),
                 filename: path.file_name().unwrap().to_string_lossy().to_string(),
                 name: path.file_stem().unwrap().to_string_lossy().to_string(),
-                extension: path.extension().unwrap().to_string_lossy().to_string(),
+                extension: path
+                    .extension()
+                    .map(|ext| ext.to_string_lossy().to_string())
+                    .unwrap_or_default(),
                 size: path.metadata()?.len(),
             },
             bitmap: DynamicImage::new_rgba8(0, 0),
             encoded_data: vec![],
+            color_byte_size: 0,
+            alpha_byte_size: 0,
+            alpha_dropped: false,
+            timings: Timings::default(),
+            encoded_dimensions: (0, 0),
             height: 0,
             width: 0,
             format: ImageFormat::Bmp,
+            page: None,
+            dir_config: None,
+            suffix: None,
+            input_format: None,
+            icc_profile: None,
+            profile_warning: None,
+            source_color: None,
+            downscaled: None,
         })
     }
 
-    pub fn load_image_data(&mut self, remove_alpha: bool) -> Result<()> {
-        let mut image_data = Reader::open(&self.metadata.path)?;
+    /// Number of pages in the source file. Anything that isn't a TIFF has a single page.
+    pub fn page_count(&self) -> usize {
+        if self.source_format() != Some(ImageFormat::Tiff) {
+            return 1;
+        }
+
+        decoders::tiff::page_count(&self.metadata.path).unwrap_or(1)
+    }
 
-        let format = ImageFormat::from_extension(&self.metadata.extension).unwrap();
+    /// Split a multi-page TIFF into one `ImageFile` per page.
+    ///
+    /// Pages are independent still images and are each encoded to their own AVIF,
+    /// they are never treated as frames of an animated AVIF.
+    pub fn split_pages(self) -> Vec<Self> {
+        let pages = self.page_count();
 
-        image_data.set_format(format);
+        if pages <= 1 {
+            return vec![self];
+        }
 
-        let mut raw_image = image_data.decode()?;
+        (0..pages)
+            .map(|page| Self {
+                page: Some(page),
+                ..self.clone()
+            })
+            .collect()
+    }
 
-        let (width, height) = (raw_image.width(), raw_image.height());
+    /// Decode the source. With `to_srgb`, pixels are converted from the embedded ICC profile
+    /// to sRGB. With a `background`, transparency is then flattened onto that color
+    pub fn load_image_data(
+        &mut self,
+        background: Option<[u8; 3]>,
+        to_srgb: bool,
+        io_retries: u32,
+    ) -> Result<()> {
+        let path = self.metadata.path.clone();
+        let mut raw_image =
+            retry_io(io_retries, &path, || self.decode()).map_err(|err| Error::Decode {
+                path: path.clone(),
+                reason: err.to_string(),
+            })?;
 
-        if width < 32 {
-            bail!("Image width too small for encode!")
+        let mut converted = false;
+        if let Some(icc) = self.icc_profile.as_ref().filter(|_| to_srgb) {
+            match decoders::icc::convert_to_srgb(&raw_image, icc) {
+                Ok(image) => {
+                    raw_image = image;
+                    converted = true;
+                }
+                Err(err) => error!(
+                    "Failed to convert {} to sRGB, encoding it as is: {err}",
+                    self.original_name()
+                ),
+            }
         }
 
-        if remove_alpha && raw_image.color().has_alpha() {
-            debug!("Replacing transparent pixels with black");
-            let mut black_square = ImageBuffer::new(width, height);
+        // Without a profile, the stored color type is all there is to go by
+        self.profile_warning = match &self.icc_profile {
+            Some(_) if converted => None,
+            Some(icc) => decoders::icc::profile_warning(icc),
+            None => self
+                .source_color
+                .and_then(decoders::icc::color_type_warning),
+        };
 
-            for (_, _, pixel) in black_square.enumerate_pixels_mut() {
-                *pixel = image::Rgba([0, 0, 0, 255]);
-            }
+        let (width, height) = (raw_image.width(), raw_image.height());
+
+        if width < 32 || height == 0 {
+            Err(Error::TooSmall {
+                path: self.metadata.path.clone(),
+                width,
+                height,
+            })?;
+        }
 
-            overlay(&mut black_square, &raw_image, 0, 0);
+        if let Some(background) = background.filter(|_| raw_image.color().has_alpha()) {
+            debug!("Flattening transparent pixels onto {background:?}");
+            let color = raw_image.color();
 
-            raw_image = DynamicImage::ImageRgba8(black_square);
+            // Keep 16-bit sources at 16 bits, the encoder can use the extra precision
+            raw_image = if color.bytes_per_pixel() > color.channel_count() {
+                let [r, g, b] = background.map(|c| u16::from(c) * 257);
+                let mut canvas =
+                    ImageBuffer::from_pixel(width, height, image::Rgba([r, g, b, u16::MAX]));
+                overlay(&mut canvas, &raw_image.to_rgba16(), 0, 0);
+                DynamicImage::ImageRgb16(DynamicImage::ImageRgba16(canvas).to_rgb16())
+            } else {
+                let [r, g, b] = background;
+                let mut canvas =
+                    ImageBuffer::from_pixel(width, height, image::Rgba([r, g, b, 255]));
+                overlay(&mut canvas, &raw_image, 0, 0);
+                DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+            };
         }
 
         self.bitmap = raw_image;
-        self.format = format;
         self.width = width;
         self.height = height;
 
         Ok(())
     }
 
+    fn decode(&mut self) -> Result<DynamicImage> {
+        // Camera raw files have no `ImageFormat`, they go through their own develop step
+        #[cfg(feature = "raw")]
+        if self.input_format.is_none() && decoders::raw::is_raw_extension(&self.metadata.extension)
+        {
+            return decoders::raw::decode_raw(&self.metadata.path);
+        }
+
+        let Some(format) = self.source_format() else {
+            Err(Error::UnsupportedFormat(self.metadata.path.clone()))?
+        };
+        self.format = format;
+
+        if let Some(page) = self.page {
+            return decoders::tiff::decode_page(&self.metadata.path, page);
+        }
+
+        if format == ImageFormat::Avif {
+            return decoders::avif::get_avif_bitmap(&self.metadata.path);
+        }
+
+        let mut image_data = Reader::open(&self.metadata.path)?;
+
+        image_data.set_format(format);
+
+        let mut decoder = image_data.into_decoder()?;
+        self.icc_profile = decoder.icc_profile().ok().flatten();
+
+        // JPEG decoders report CMYK files as the RGB they convert them to
+        self.source_color = match format {
+            ImageFormat::Jpeg
+                if decoders::jpeg::component_count(&self.metadata.path).is_ok_and(|n| n == 4) =>
+            {
+                Some(ExtendedColorType::Cmyk8)
+            }
+            _ => Some(decoder.original_color_type()),
+        };
+
+        Ok(DynamicImage::from_decoder(decoder)?)
+    }
+
+    /// `--input-format` if given, otherwise what the extension says
+    fn source_format(&self) -> Option<ImageFormat> {
+        self.input_format
+            .or_else(|| ImageFormat::from_extension(&self.metadata.extension))
+    }
+
+    /// Decode the source and run the filters. Only the first call does any work,
+    /// later encodes reuse the bitmap
+    pub fn prepare(&mut self, options: &EncodeOptions) -> Result<()> {
+        if self.bitmap.as_bytes().is_empty() {
+            let start = Instant::now();
+
+            self.load_image_data(
+                options.background,
+                options.convert_to_srgb,
+                options.io_retries,
+            )?;
+
+            if let Some(crop) = options.crop {
+                if !crop.fits(self.width, self.height) {
+                    Err(Error::CropOutOfBounds {
+                        path: self.metadata.path.clone(),
+                        width: self.width,
+                        height: self.height,
+                    })?;
+                }
+
+                self.bitmap = crop.apply(&self.bitmap);
+                self.width = crop.width;
+                self.height = crop.height;
+            }
+
+            for filter in &options.filters {
+                self.bitmap = filter.apply(&self.bitmap);
+            }
+
+            // Rotating by 90 or 270 degrees swaps the dimensions
+            self.width = self.bitmap.width();
+            self.height = self.bitmap.height();
+
+            self.timings.decode = start.elapsed();
+        }
+
+        if self.bitmap.as_bytes().is_empty() || self.width < 32 || self.height == 0 {
+            Err(Error::TooSmall {
+                path: self.metadata.path.clone(),
+                width: self.width,
+                height: self.height,
+            })?;
+        }
+
+        Ok(())
+    }
+
     pub fn convert_to_avif_stored(
         &mut self,
-        quality: u8,
-        speed: u8,
-        threads: usize,
-        depth: u8,
-        remove_alpha: bool,
+        options: &EncodeOptions,
         progress: Option<ProgressBar>,
     ) -> Result<u64> {
-        if self.bitmap.as_bytes().is_empty() {
-            self.load_image_data(remove_alpha)?;
-        }
+        self.prepare(options)?;
 
-        assert!(!self.bitmap.as_bytes().is_empty());
+        let alpha_quality = if options.lossless_alpha {
+            100.
+        } else {
+            options.quality as f32
+        };
 
         let encoder = Encoder::new()
-            .with_num_threads(threads)
-            .with_alpha_quality(quality as f32)
-            .with_quality(quality as f32)
-            .with_speed(speed)
-            .with_bit_depth(depth);
+            .with_num_threads(options.threads)
+            .with_alpha_quality(alpha_quality)
+            .with_quality(options.quality as f32)
+            .with_speed(options.speed)
+            .with_bit_depth(options.bit_depth)
+            .with_bitrate(options.bitrate)
+            .with_quantizer_range(options.min_quantizer, options.max_quantizer)
+            .with_hdr_metadata(options.hdr)
+            .with_matrix(options.matrix)
+            .with_primaries(options.primaries)
+            .with_gamut_conversion(options.convert_gamut)
+            .with_monochrome(options.monochrome)
+            .with_chroma_subsampling(options.subsampling)
+            .with_still_picture(options.still_picture)
+            .with_opaque_threshold(options.opaque_threshold)
+            .with_timeout(options.timeout);
+
+        // Encode a downscaled copy, the full size bitmap is kept for the other outputs
+        let full_size = match options.width {
+            Some(width) if width < self.width => {
+                let resized = self.downscale(width);
+                let height = resized.height();
+                self.downscaled = Some(resized.clone());
+
+                Some((
+                    std::mem::replace(&mut self.bitmap, resized),
+                    std::mem::replace(&mut self.width, width),
+                    std::mem::replace(&mut self.height, height),
+                ))
+            }
+            _ => None,
+        };
+
+        let encoded = encoder.encode(self);
+
+        if let Some((bitmap, width, height)) = full_size {
+            self.bitmap = bitmap;
+            self.width = width;
+            self.height = height;
+        }
+
+        encoded?;
 
-        encoder.encode(self)?;
+        debug!(
+            "AV1 payload of {}: {} color, {} alpha",
+            self.original_name(),
+            self.color_byte_size,
+            self.alpha_byte_size
+        );
+        debug!(
+            "Timings of {}: decode {:?}, convert {:?}, color {:?}, alpha {:?}",
+            self.original_name(),
+            self.timings.decode,
+            self.timings.convert,
+            self.timings.color,
+            self.timings.alpha
+        );
 
         if let Some(pb) = progress {
             pb.inc(1);
@@ -131,17 +562,186 @@ impl ImageFile {
         Ok(self.encoded_data.len() as u64)
     }
 
-    pub fn save_avif(&self, path: Option<PathBuf>, name: Name, keep: bool) -> Result<()> {
-        let fname = name.generate_name(self);
+    /// Bitmap resized to `width`, starting from the last downscale when it's at least as wide
+    fn downscale(&self, width: u32) -> DynamicImage {
+        // Always from the full size, rounding a downscale again could be off by a row
+        let height = scaled_height(self.width, self.height, width);
+
+        match &self.downscaled {
+            Some(cached) if cached.width() == width => cached.clone(),
+            Some(cached) if cached.width() > width => {
+                debug!("Resizing {} from {}w", self.original_name(), cached.width());
+                cached.resize_exact(width, height, FilterType::Lanczos3)
+            }
+            _ => self
+                .bitmap
+                .resize_exact(width, height, FilterType::Lanczos3),
+        }
+    }
+
+    /// Fraction of the output taken by the alpha channel, `None` when it has no alpha
+    pub fn alpha_overhead(&self) -> Option<f64> {
+        (self.alpha_byte_size > 0 && !self.encoded_data.is_empty())
+            .then(|| self.alpha_byte_size as f64 / self.encoded_data.len() as f64)
+    }
+
+    pub fn set_encoded(&mut self, encoded: EncodedImage) {
+        self.encoded_data = encoded.avif_file;
+        self.color_byte_size = encoded.color_byte_size;
+        self.alpha_byte_size = encoded.alpha_byte_size;
+        self.alpha_dropped = encoded.alpha_dropped;
+        // Called while a `--widths` downscale stands in for the bitmap
+        self.encoded_dimensions = (self.width, self.height);
+        // Later outputs reuse the bitmap, so they keep the original decode time
+        self.timings = Timings {
+            decode: self.timings.decode,
+            ..encoded.timings
+        };
+    }
+
+    /// Name of the output file, from the name generator and the save options
+    pub fn output_file_name(&self, options: &SaveOptions) -> String {
+        let fname = format!(
+            "{}{}",
+            options
+                .name
+                .generate_name(self, options.name_suffix.as_deref()),
+            self.suffix.as_deref().unwrap_or_default()
+        );
+
+        let avif_ext = if options.match_extension_case
+            && self
+                .metadata
+                .extension
+                .chars()
+                .all(|c| c.is_ascii_uppercase())
+        {
+            "AVIF"
+        } else {
+            "avif"
+        };
+
+        // `photo.jpg` becomes `photo.jpg.avif` so the source format can be recovered from the name
+        if options.keep_extension && !self.metadata.extension.is_empty() {
+            format!("{fname}.{}.{avif_ext}", self.metadata.extension)
+        } else {
+            format!("{fname}.{avif_ext}")
+        }
+    }
+
+    pub fn save_avif(&self, path: Option<PathBuf>, options: &SaveOptions) -> Result<SavedFile> {
+        let file_name = self.output_file_name(options);
+
+        // The source still holds the other pages (or feeds the other variants),
+        // so it must never be replaced by one of them
+        let keep = options.keep || self.page.is_some() || self.suffix.is_some();
+
+        if options.backup && !keep {
+            self.back_up_source()?;
+        }
+
+        // Hash names mean an existing file with the same name most likely has the same content
+        if options.dedup && options.name.is_content_hash() {
+            let target = self.output_path(path.as_deref(), &file_name)?;
+
+            if fs::read(&target).is_ok_and(|existing| existing == self.encoded_data) {
+                debug!("{} duplicates {}", self.original_name(), target.display());
+
+                if !keep {
+                    fs::remove_file(&self.metadata.path)?;
+                }
+
+                return Ok(SavedFile {
+                    path: target,
+                    duplicate: true,
+                });
+            }
+        }
+
+        // Read before writing, the source may be gone afterwards
+        let source_metadata = if options.preserve_perms {
+            Some(fs::metadata(&self.metadata.path)?)
+        } else {
+            None
+        };
+
+        // The original gets overwritten in place, so hold on to it until the output is verified
+        let original = if options.verify && !keep {
+            Some(fs::read(&self.metadata.path)?)
+        } else {
+            None
+        };
+
+        let output = retry_io(options.io_retries, &self.metadata.path, || {
+            match &options.tmpdir {
+                Some(tmpdir) => self.write_avif_staged(path.clone(), &file_name, keep, tmpdir),
+                None => self.write_avif(path.clone(), &file_name, keep),
+            }
+        })?;
+
+        if options.verify {
+            if let Err(error) = self.verify_output(&output) {
+                fs::remove_file(&output)?;
+
+                if let Some(original) = original {
+                    fs::write(&self.metadata.path, original)?;
+                }
+
+                return Err(error);
+            }
+        }
+
+        if let Some(source_metadata) = source_metadata {
+            copy_permissions(&source_metadata, &output)?;
+        }
+
+        Ok(SavedFile {
+            path: output,
+            duplicate: false,
+        })
+    }
+
+    /// Where `write_avif` puts `file_name`: inside `path` if given, next to the source otherwise
+    fn output_path(&self, path: Option<&Path>, file_name: &str) -> Result<PathBuf> {
+        match path {
+            Some(new_path) => Ok(new_path.join(file_name)),
+            None => {
+                let binding = self.metadata.path.canonicalize()?;
+                Ok(binding.parent().unwrap().join(file_name))
+            }
+        }
+    }
+
+    /// Check that the file written to `output` matches the encoded data byte for byte
+    fn verify_output(&self, output: &Path) -> Result<()> {
+        let written = fs::read(output)?;
+
+        if written.len() != self.encoded_data.len() {
+            bail!(
+                "Verification failed for {:?}: expected {} bytes, found {}",
+                output,
+                self.encoded_data.len(),
+                written.len()
+            )
+        }
+
+        if written != self.encoded_data {
+            bail!("Verification failed for {:?}: contents differ", output)
+        }
 
+        Ok(())
+    }
+
+    /// Write the encoded data to disk and return the path of the new file
+    fn write_avif(&self, path: Option<PathBuf>, file_name: &str, keep: bool) -> Result<PathBuf> {
         let binding = self.metadata.path.canonicalize()?;
         let fpath = binding.parent().unwrap();
 
-        let avif_name = fpath.join(format!("{fname}.avif"));
+        let avif_name = fpath.join(file_name);
 
         // If `path` is Some, save to the provided path
         if let Some(new_path) = path {
-            let target_avif_name = new_path.join(format!("{fname}.avif"));
+            let target_avif_name = new_path.join(file_name);
 
             if !keep {
                 // If `keep` is false and we have a target path, we want to replace the original file
@@ -152,7 +752,7 @@ impl ImageFile {
 
                 // Attempt to rename (move) to the new path
                 match fs::rename(&binding, &target_avif_name) {
-                    Ok(_) => return Ok(()), // Success, file moved
+                    Ok(_) => return Ok(target_avif_name), // Success, file moved
                     Err(_) => {
                         // Rename failed (likely due to different filesystems), fallback to copy+delete
                         fs::copy(&binding, &target_avif_name)?;
@@ -160,13 +760,13 @@ impl ImageFile {
                     }
                 }
 
-                return Ok(());
+                return Ok(target_avif_name);
             }
 
             // If `keep` is true, just save the AVIF to the target location
             fs::write(&target_avif_name, &self.encoded_data)?;
 
-            return Ok(());
+            return Ok(target_avif_name);
         }
 
         // If no `path` is provided, proceed with in-place modifications
@@ -179,16 +779,331 @@ impl ImageFile {
             // Rename (move) the file to the new AVIF name
             fs::rename(&binding, &avif_name)?;
 
-            return Ok(());
+            return Ok(avif_name);
         }
 
         // If `keep` is true, save AVIF to the same directory
         fs::write(&avif_name, &self.encoded_data)?;
 
+        Ok(avif_name)
+    }
+
+    /// Copy the source to `photo.jpg.orig`. It's a copy rather than a rename because every
+    /// write path replaces the source in place, which needs it to still be there
+    fn back_up_source(&self) -> Result<()> {
+        let mut backup = self.metadata.path.clone().into_os_string();
+        backup.push(".orig");
+
+        debug!("Backing up {} to {backup:?}", self.original_name());
+        fs::copy(&self.metadata.path, backup)?;
+
         Ok(())
     }
 
+    /// Write the encoded data to a temporary file in `tmpdir`, then move it to its final
+    /// location. The source directory is only touched by the final move.
+    fn write_avif_staged(
+        &self,
+        path: Option<PathBuf>,
+        file_name: &str,
+        keep: bool,
+        tmpdir: &Path,
+    ) -> Result<PathBuf> {
+        let target = self.output_path(path.as_deref(), file_name)?;
+        let source = self.metadata.path.canonicalize()?;
+
+        // Workers share `tmpdir` and hash names can repeat, so every staged file gets its own name
+        let staged = tmpdir.join(format!(
+            ".{file_name}.{}.{}.tmp",
+            process::id(),
+            STAGED_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        fs::write(&staged, &self.encoded_data)?;
+
+        match fs::rename(&staged, &target) {
+            Ok(()) => {}
+            // `tmpdir` is usually on another filesystem, where a rename isn't possible
+            Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+                debug!("{staged:?} is on another device, copying instead");
+                fs::copy(&staged, &target)?;
+                fs::remove_file(&staged)?;
+            }
+            Err(err) => {
+                fs::remove_file(&staged)?;
+                return Err(err.into());
+            }
+        }
+
+        // An output with the same name as its source has already replaced it
+        if !keep && target.canonicalize()? != source {
+            fs::remove_file(&self.metadata.path)?;
+        }
+
+        Ok(target)
+    }
+
+    /// SSIM of the encoded data against the source bitmap, see [`crate::ssim::measure_ssim`]
+    #[cfg(feature = "ssim")]
+    pub fn measure_ssim(&self, scale: f32) -> Result<(f64, image::GrayImage)> {
+        let encoded = decoders::avif::decode_avif_bytes(&self.encoded_data)?;
+
+        // `--widths` outputs are smaller than the source, compare against a matching downscale
+        if (encoded.width(), encoded.height()) != (self.bitmap.width(), self.bitmap.height()) {
+            let original = match &self.downscaled {
+                Some(cached)
+                    if cached.width() == encoded.width() && cached.height() == encoded.height() =>
+                {
+                    cached.clone()
+                }
+                _ => self.bitmap.resize_exact(
+                    encoded.width(),
+                    encoded.height(),
+                    FilterType::Lanczos3,
+                ),
+            };
+
+            return Ok(crate::ssim::measure_ssim(&original, &encoded, scale));
+        }
+
+        Ok(crate::ssim::measure_ssim(&self.bitmap, &encoded, scale))
+    }
+
+    /// Where the SSIM difference image for this output is saved. The page and the
+    /// `--widths`/`--quality-set` suffix keep outputs of the same source apart
+    #[cfg(feature = "ssim")]
+    pub fn ssim_diff_path(&self) -> PathBuf {
+        let page = self
+            .page
+            .map(|page| format!("_p{}", page + 1))
+            .unwrap_or_default();
+
+        self.metadata.path.with_file_name(format!(
+            "{}{page}{}_ssim.png",
+            self.metadata.name,
+            self.suffix.as_deref().unwrap_or_default()
+        ))
+    }
+
+    /// Central `size`x`size` crop of the source and of the decoded AVIF, side by side
+    pub fn quality_preview(&self, size: u32) -> Result<RgbImage> {
+        let encoded = decoders::avif::decode_avif_bytes(&self.encoded_data)?;
+        let (width, height) = (encoded.width(), encoded.height());
+
+        let original = if (width, height) != (self.bitmap.width(), self.bitmap.height()) {
+            self.bitmap
+                .resize_exact(width, height, FilterType::Lanczos3)
+        } else {
+            self.bitmap.clone()
+        };
+
+        let size = size.min(width).min(height);
+        let (x, y) = ((width - size) / 2, (height - size) / 2);
+
+        let mut preview = RgbImage::new(size * 2, size);
+        replace(
+            &mut preview,
+            &original.crop_imm(x, y, size, size).to_rgb8(),
+            0,
+            0,
+        );
+        replace(
+            &mut preview,
+            &encoded.crop_imm(x, y, size, size).to_rgb8(),
+            i64::from(size),
+            0,
+        );
+
+        Ok(preview)
+    }
+
+    /// Where the `--preview` image for this file is saved
+    pub fn preview_path(&self) -> PathBuf {
+        self.metadata
+            .path
+            .with_file_name(format!("{}_preview.png", self.metadata.name))
+    }
+
     pub fn original_name(&self) -> String {
         self.metadata.filename.clone()
     }
 }
+
+/// Height that keeps the aspect ratio of a `source_width` x `source_height` image at `width`
+fn scaled_height(source_width: u32, source_height: u32, width: u32) -> u32 {
+    ((source_height as f64 * width as f64 / source_width as f64).round() as u32).max(1)
+}
+
+/// Bits per channel actually carrying data. 16-bit images holding 8-bit values scaled up
+/// (`v * 257`) or shifted up (`v << 8`) count as 8 bits. Float images count as 16
+pub fn effective_bit_depth(image: &DynamicImage) -> u8 {
+    let color = image.color();
+
+    if color.bytes_per_pixel() == color.channel_count() {
+        return 8;
+    }
+
+    let samples: &[u16] = match image {
+        DynamicImage::ImageLuma16(buffer) => buffer.as_raw(),
+        DynamicImage::ImageLumaA16(buffer) => buffer.as_raw(),
+        DynamicImage::ImageRgb16(buffer) => buffer.as_raw(),
+        DynamicImage::ImageRgba16(buffer) => buffer.as_raw(),
+        _ => return 16,
+    };
+
+    if samples.iter().all(|&sample| sample % 257 == 0) {
+        return 8;
+    }
+
+    // Data shifted up from a lower depth leaves the bottom bits empty
+    let unused = samples
+        .iter()
+        .map(|sample| sample.trailing_zeros())
+        .min()
+        .unwrap_or(0);
+
+    (16 - unused.min(8)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, io::Cursor, process};
+
+    use image::{RgbImage, Rgba};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("avif-converter-{}-{name}", process::id()))
+    }
+
+    #[test]
+    fn truncated_png_is_an_error() {
+        let mut png = Vec::new();
+        RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let path = temp_path("truncated.png");
+        fs::write(&path, &png[..png.len() / 2]).unwrap();
+
+        let mut image = ImageFile::open(&path, None).unwrap();
+        let result = image.prepare(&EncodeOptions::default());
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rgba16_tiff_round_trip() {
+        // Steps of 16 are lost at 8 bits but kept at 12
+        let source = ImageBuffer::from_fn(64, 48, |x, y| {
+            Rgba([x as u16 * 16, y as u16 * 16, 40_000, (x + y) as u16 * 500])
+        });
+        let path = temp_path("rgba16.tiff");
+        DynamicImage::ImageRgba16(source.clone())
+            .save(&path)
+            .unwrap();
+
+        let mut image = ImageFile::open(&path, None).unwrap();
+        let options = EncodeOptions {
+            speed: 10,
+            bit_depth: 12,
+            ..EncodeOptions::default()
+        };
+        let result = image.convert_to_avif_stored(&options, None);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(image.bitmap.as_rgba16(), Some(&source));
+        assert!(image.alpha_byte_size > 0);
+
+        let alpha_urn = b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha";
+        assert!(image
+            .encoded_data
+            .windows(alpha_urn.len())
+            .any(|window| window == alpha_urn));
+    }
+
+    #[test]
+    fn cmyk_jpeg_decodes_to_rgb() {
+        // 32x16 of full cyan ink, stored inverted with an Adobe marker like Photoshop does
+        let path = temp_path("cmyk.jpg");
+        fs::write(&path, include_bytes!("../tests/fixtures/cmyk-cyan.jpg")).unwrap();
+
+        let mut image = ImageFile::open(&path, None).unwrap();
+        let result = image.prepare(&EncodeOptions::default());
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(image.bitmap.to_rgb8().get_pixel(8, 8).0, [0, 255, 255]);
+
+        // There's no profile to convert with, so the warning stays even with `--convert-to-srgb`
+        let warning = image
+            .profile_warning
+            .expect("CMYK source without a warning");
+        assert_eq!(warning.to_string(), "CMYK pixels without a profile");
+    }
+
+    #[cfg(feature = "ssim")]
+    #[test]
+    fn ssim_diff_paths_are_per_output() {
+        let path = temp_path("scan.tiff");
+        fs::write(&path, b"").unwrap();
+        let image = ImageFile::open(&path, Some(ImageFormat::Tiff));
+        fs::remove_file(&path).unwrap();
+
+        let image = ImageFile {
+            page: Some(1),
+            suffix: Some("_640w".to_string()),
+            ..image.unwrap()
+        };
+
+        let name = image.ssim_diff_path();
+        assert_eq!(
+            name.file_name().unwrap().to_string_lossy(),
+            format!("avif-converter-{}-scan_p2_640w_ssim.png", process::id())
+        );
+    }
+
+    /// No metadata is carried over, a huge ComfyUI `workflow` text chunk can't break the output
+    #[test]
+    fn large_text_chunk_is_dropped() {
+        let mut png = Vec::new();
+        RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8, y as u8, 0]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let mut text = b"workflow\0".to_vec();
+        text.resize(100 * 1024, b'{');
+        let mut chunk = (text.len() as u32).to_be_bytes().to_vec();
+        chunk.extend(b"tEXt");
+        chunk.extend(&text);
+        chunk.extend(crc32fast::hash(&chunk[4..]).to_be_bytes());
+
+        // Right after the signature and IHDR
+        png.splice(33..33, chunk);
+
+        let path = temp_path("workflow.png");
+        fs::write(&path, &png).unwrap();
+
+        let mut image = ImageFile::open(&path, None).unwrap();
+        let options = EncodeOptions {
+            speed: 10,
+            ..EncodeOptions::default()
+        };
+        let result = image.convert_to_avif_stored(&options, None);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        // Top level boxes have to add up to the file size
+        let data = &image.encoded_data;
+        let mut offset = 0;
+        while offset < data.len() {
+            offset += u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        }
+        assert_eq!(offset, data.len());
+        assert!(data.len() < 100 * 1024);
+        assert!(!data.windows(4).any(|window| window == b"Exif"));
+    }
+}
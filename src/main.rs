@@ -3,13 +3,19 @@ use cli::{
     Args,
 };
 use color_eyre::eyre::Result;
+use encoders::avif::encode::ENCODER_LOG_TARGET;
 
+mod archive;
+mod change_index;
 mod cli;
+mod config;
 mod console;
 mod decoders;
 mod encoders;
+mod filter;
 mod image_file;
 mod name_fun;
+mod resume;
 mod utils;
 
 #[cfg(feature = "ssim")]
@@ -17,12 +23,23 @@ mod ssim;
 
 fn main() -> Result<()> {
     color_eyre::install()?;
-    env_logger::builder().format_timestamp(None).init();
     let args: Args = Args::init();
+
+    let mut logger = env_logger::builder();
+    logger.format_timestamp(None);
+    if args.encoder_log {
+        logger.filter_module(ENCODER_LOG_TARGET, log::LevelFilter::Info);
+    }
+    logger.init();
     let globals = args.clone(); // Inneficient as fuck but whatever
 
     match args.command {
         Commands::Avif(dtd) => dtd.run_conv(&globals),
         Commands::Watch(dtd) => dtd.watch_folder(&globals),
+        #[cfg(feature = "ssim")]
+        Commands::Compare(dtd) => dtd.run(&globals),
+        Commands::ListFormats(dtd) => dtd.run(&globals),
+        Commands::Decode(dtd) => dtd.run(&globals),
+        Commands::Verify(dtd) => dtd.run(&globals),
     }
 }
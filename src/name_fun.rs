@@ -1,11 +1,13 @@
 use blake2::{digest::typenum::U16, Blake2b, Digest as B2Digest};
 use clap::ValueEnum;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::Deserialize;
 use sha2::Sha256;
 
 use crate::image_file::ImageFile;
 
-#[derive(Debug, ValueEnum, Copy, Clone, Default)]
+#[derive(Debug, ValueEnum, Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum Name {
     #[default]
@@ -19,7 +21,8 @@ pub enum Name {
 type Blake2b32char = Blake2b<U16>;
 
 impl Name {
-    pub fn generate_name(self, data: &ImageFile) -> String {
+    /// `suffix` only applies to `Same`, hash and random names are already distinct from the source
+    pub fn generate_name(self, data: &ImageFile, suffix: Option<&str>) -> String {
         match self {
             Name::MD5 => {
                 let digest = md5::compute(&data.encoded_data);
@@ -39,10 +42,22 @@ impl Name {
                 hex::encode(hasher.finalize())
             }
             Name::Random => Self::random_string(),
-            Name::Same => data.metadata.name.clone(),
+            Name::Same => {
+                let suffix = suffix.unwrap_or_default();
+
+                match data.page {
+                    Some(page) => format!("{}{suffix}_p{}", data.metadata.name, page + 1),
+                    None => format!("{}{suffix}", data.metadata.name),
+                }
+            }
         }
     }
 
+    /// Whether the generated name is derived from the encoded bytes
+    pub fn is_content_hash(self) -> bool {
+        matches!(self, Name::MD5 | Name::SHA256 | Name::Blake2)
+    }
+
     fn random_string() -> String {
         let s = thread_rng()
             .sample_iter(&Alphanumeric)
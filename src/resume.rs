@@ -0,0 +1,67 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+};
+
+use color_eyre::Result;
+use log::{debug, error};
+
+use crate::image_file::ImageFile;
+
+/// Checkpoint of a batch job, one finished input per line.
+///
+/// Lines are appended as soon as a file is saved, so a killed job loses at most the
+/// files that were still being encoded.
+#[derive(Debug)]
+pub struct ResumeState {
+    done: HashSet<String>,
+    file: Mutex<File>,
+}
+
+impl ResumeState {
+    /// Read the finished inputs from `path`, creating the file if it doesn't exist
+    pub fn open(path: &Path) -> Result<Self> {
+        let done: HashSet<String> = match fs::read_to_string(path) {
+            Ok(contents) => contents.lines().map(str::to_string).collect(),
+            Err(_) => HashSet::new(),
+        };
+
+        debug!("Loaded {} finished files from {path:?}", done.len());
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            done,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn is_done(&self, image: &ImageFile) -> bool {
+        self.done.contains(&Self::key(image))
+    }
+
+    pub fn mark_done(&self, image: &ImageFile) {
+        let mut file = self.file.lock().unwrap();
+
+        if let Err(err) = writeln!(file, "{}", Self::key(image)) {
+            error!("Failed to record {} as done: {err}", image.original_name());
+        }
+    }
+
+    /// Canonical path, plus the page for multi-page TIFFs
+    fn key(image: &ImageFile) -> String {
+        let path = image
+            .metadata
+            .path
+            .canonicalize()
+            .unwrap_or_else(|_| image.metadata.path.clone());
+
+        match image.page {
+            Some(page) => format!("{}#{page}", path.display()),
+            None => path.display().to_string(),
+        }
+    }
+}
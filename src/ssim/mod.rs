@@ -1,16 +1,16 @@
-use image::{GrayImage, Luma};
+use image::{imageops::FilterType, DynamicImage, GrayImage, Luma, Rgb, RgbImage};
 use rayon::prelude::*;
 
 pub fn calculate_ssim_and_diff(img1: &GrayImage, img2: &GrayImage) -> (f64, GrayImage) {
     assert_eq!(img1.dimensions(), img2.dimensions());
 
     let (width, height) = img1.dimensions();
-    let mut diff_image = GrayImage::new(width, height); // To store the difference image
 
-    let total_ssim: f64 = (0..height)
+    let rows: Vec<(f64, Vec<u8>)> = (0..height)
         .into_par_iter()
         .map(|y| {
             let mut ssim_row_total = 0.0;
+            let mut diff_row = Vec::with_capacity(width as usize);
 
             for x in 0..width {
                 let p1 = img1.get_pixel(x, y)[0] as f64;
@@ -35,15 +35,101 @@ pub fn calculate_ssim_and_diff(img1: &GrayImage, img2: &GrayImage) -> (f64, Gray
 
                 // Generate difference image by scaling the absolute difference
                 let diff_value = ((p1 - p2).abs() * 255.0 / 255.0) as u8; // Scale the difference to fit 0-255 range
-                diff_image.put_pixel(x, y, Luma([diff_value])); // Store difference in diff image
+                diff_row.push(diff_value); // Store difference for this row
             }
 
-            ssim_row_total
+            (ssim_row_total, diff_row)
         })
-        .sum(); // Sum all the rows' SSIM totals in parallel
+        .collect(); // Compute all the rows in parallel
+
+    let mut diff_image = GrayImage::new(width, height); // To store the difference image
+    let mut total_ssim = 0.0;
+
+    for (y, (ssim_row_total, diff_row)) in rows.into_iter().enumerate() {
+        total_ssim += ssim_row_total;
+
+        for (x, diff_value) in diff_row.into_iter().enumerate() {
+            diff_image.put_pixel(x as u32, y as u32, Luma([diff_value]));
+        }
+    }
 
     // Compute the final SSIM score (average over all pixels)
     let avg_ssim = total_ssim / (width * height) as f64;
 
     (avg_ssim, diff_image)
 }
+
+/// Map a difference image to a blue (identical) → red (very different) heatmap
+pub fn apply_colormap(diff: &GrayImage) -> RgbImage {
+    RgbImage::from_fn(diff.width(), diff.height(), |x, y| {
+        let v = diff.get_pixel(x, y)[0] as f32 / 255.0;
+
+        let r = (1.5 - (4.0 * v - 3.0).abs()).clamp(0.0, 1.0);
+        let g = (1.5 - (4.0 * v - 2.0).abs()).clamp(0.0, 1.0);
+        let b = (1.5 - (4.0 * v - 1.0).abs()).clamp(0.0, 1.0);
+
+        Rgb([(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8])
+    })
+}
+
+/// Blend a heatmap on top of the base image. `opacity` is the heatmap weight in `0..=1`
+pub fn overlay_images(base: &DynamicImage, heatmap: &RgbImage, opacity: f32) -> RgbImage {
+    let base = base.to_rgb8();
+
+    RgbImage::from_fn(base.width(), base.height(), |x, y| {
+        let b = base.get_pixel(x, y);
+        let h = heatmap.get_pixel(x, y);
+
+        Rgb([0, 1, 2]
+            .map(|c| (f32::from(b[c]) * (1.0 - opacity) + f32::from(h[c]) * opacity).round() as u8))
+    })
+}
+
+/// SSIM of `encoded` against `original`.
+///
+/// With a `scale` below 1 both images are downscaled by the same factor before comparing.
+/// This is much faster on huge images, but the absolute SSIM value shifts slightly with the scale.
+pub fn measure_ssim(
+    original: &DynamicImage,
+    encoded: &DynamicImage,
+    scale: f32,
+) -> (f64, GrayImage) {
+    let to_luma = |image: &DynamicImage| {
+        if scale < 1.0 {
+            let width = ((image.width() as f32 * scale).round() as u32).max(1);
+            let height = ((image.height() as f32 * scale).round() as u32).max(1);
+
+            image
+                .resize_exact(width, height, FilterType::Triangle)
+                .to_luma8()
+        } else {
+            image.to_luma8()
+        }
+    };
+
+    calculate_ssim_and_diff(&to_luma(original), &to_luma(encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// There is no window to outgrow, small images and tiny scales still compare
+    #[test]
+    fn small_images_compare() {
+        let original = DynamicImage::ImageRgb8(RgbImage::from_fn(40, 40, |x, y| {
+            Rgb([x as u8 * 6, y as u8 * 6, 128])
+        }));
+
+        for scale in [1.0, 0.5, 0.01] {
+            let (score, diff) = measure_ssim(&original, &original, scale);
+
+            assert!((score - 1.0).abs() < 1e-9, "scale {scale}");
+            assert!(diff.pixels().all(|px| px[0] == 0));
+        }
+
+        let (score, diff) = measure_ssim(&original, &original.grayscale(), 1.0);
+        assert!(score < 1.0);
+        assert_eq!(diff.dimensions(), (40, 40));
+    }
+}
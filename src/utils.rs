@@ -1,33 +1,84 @@
-use std::{fmt::Write, fs, path::PathBuf};
+use std::{
+    fmt::Write,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process::Command,
+    thread::sleep,
+    time::Duration,
+};
 
+use color_eyre::eyre::{Report, Result};
+use image::{ImageError, ImageFormat};
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use log::{debug, warn};
 use once_cell::sync::Lazy;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
-use crate::image_file::ImageFile;
+use crate::{config::DirConfig, image_file::ImageFile};
 
 pub static PROGRESS_BAR: Lazy<ProgressBar> =
     Lazy::new(|| ProgressBar::new(0).with_style(bar_style()));
 
-pub fn parse_files(paths: &Vec<PathBuf>) -> Vec<ImageFile> {
+/// Collect every supported image from `paths`. Directory scans skip `.avif` files
+/// unless `include_avif` is set, explicitly listed files are always kept.
+/// Listed files are decoded as `input_format` when given, see [`ImageFile::open`].
+/// In directories it only applies to files without a supported extension
+pub fn parse_files(
+    paths: &[PathBuf],
+    include_avif: bool,
+    input_format: Option<ImageFormat>,
+) -> Vec<ImageFile> {
     paths
         .iter()
         .flat_map(|item| {
             if item.is_dir() {
                 // If it's a directory, we attempt to read the directory entries
                 if let Ok(dir) = fs::read_dir(item) {
-                    // Flatten the directory iterator, map each entry to ImageFile, and collect results
-                    dir.flatten()
-                        .filter_map(|entry| {
-                            // Try to create an ImageFile from the entry path
-                            ImageFile::new_from_path(&entry.path()).ok()
+                    // Config files are resolved once per directory, not per file
+                    let config = DirConfig::find(item);
+
+                    let entries: Vec<PathBuf> = dir
+                        .flatten()
+                        .map(|entry| entry.path())
+                        // Skip our own outputs so a re-run doesn't encode them again
+                        .filter(|path| include_avif || !is_avif(path))
+                        .collect();
+
+                    // Every entry gets a `stat` and TIFFs get opened to count pages,
+                    // which adds up in huge directories. The order is kept
+                    entries
+                        .into_par_iter()
+                        .filter_map(|path| {
+                            // Known extensions decode as themselves, `--input-format` only
+                            // picks up the files nothing else would
+                            ImageFile::new_from_path(&path)
+                                .or_else(|err| match input_format {
+                                    // Hidden files, `.avifconvert.toml` among them, aren't images
+                                    Some(format) if path.is_file() && !is_hidden(&path) => {
+                                        ImageFile::open(&path, Some(format))
+                                    }
+                                    _ => Err(err),
+                                })
+                                .ok()
+                        })
+                        .map(|mut image| {
+                            image.dir_config = config.clone();
+                            image
                         })
-                        .collect::<Vec<ImageFile>>() // Collect directory entries into a vector
+                        .flat_map_iter(ImageFile::split_pages) // Multi-page TIFFs yield one item per page
+                        .collect::<Vec<ImageFile>>()
                 } else {
                     Vec::new() // If directory read fails, return an empty Vec
                 }
             } else if item.is_file() {
                 // If it's a file, try to create an ImageFile from it
-                ImageFile::new_from_path(item).ok().into_iter().collect()
+                ImageFile::open(item, input_format)
+                    .map(|mut image| {
+                        image.dir_config = DirConfig::for_file(item);
+                        image.split_pages()
+                    })
+                    .unwrap_or_default()
             } else {
                 Vec::new() // If it's neither a file nor a directory, return an empty Vec
             }
@@ -35,6 +86,77 @@ pub fn parse_files(paths: &Vec<PathBuf>) -> Vec<ImageFile> {
         .collect()
 }
 
+/// Whether the file name of `path` starts with a dot
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Whether `path` has an `.avif` extension, in any case
+pub fn is_avif(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("avif"))
+}
+
+/// Run the `--exec` command for a converted file. The paths and the ratio (output size /
+/// input size) are handed over in the `AVIF_INPUT`, `AVIF_OUTPUT` and `AVIF_RATIO`
+/// environment variables, `{input}`, `{output}` and `{ratio}` in the template expand to
+/// them as one quoted word. Nothing from a file name is ever parsed by the shell.
+/// Failures are only logged, the conversion itself succeeded.
+pub fn run_exec_hook(template: &str, input: &Path, output: &Path, ratio: f64) {
+    let command = hook_command(template);
+
+    debug!("Running hook: {command}");
+
+    let mut shell = if cfg!(windows) {
+        // Delayed `!VAR!` expansion happens after the line is parsed, `%VAR%` would not be safe
+        let mut shell = Command::new("cmd");
+        shell.args(["/V:ON", "/C", &command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", &command]);
+        shell
+    };
+
+    let status = shell
+        .env("AVIF_INPUT", input)
+        .env("AVIF_OUTPUT", output)
+        .env("AVIF_RATIO", format!("{ratio:.4}"))
+        .status();
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("Hook for {} exited with {status}", input.display()),
+        Err(err) => warn!("Failed to run hook for {}: {err}", input.display()),
+    }
+}
+
+/// Point the `--exec` placeholders at the environment variables. Placeholders the template
+/// already wraps in quotes lose them, the variable reference brings its own
+fn hook_command(template: &str) -> String {
+    let mut command = template.to_string();
+
+    for (placeholder, var) in [
+        ("{input}", "AVIF_INPUT"),
+        ("{output}", "AVIF_OUTPUT"),
+        ("{ratio}", "AVIF_RATIO"),
+    ] {
+        let reference = if cfg!(windows) {
+            format!("\"!{var}!\"")
+        } else {
+            format!("\"${var}\"")
+        };
+
+        for quoted in [format!("\"{placeholder}\""), format!("'{placeholder}'")] {
+            command = command.replace(&quoted, placeholder);
+        }
+        command = command.replace(placeholder, &reference);
+    }
+
+    command
+}
+
 pub fn bar_style() -> ProgressStyle {
     let template = "{spinner:.red.bold} {elapsed_precise:.bold} [{wide_bar:.blue.bold}] {percent:.bold} {pos:.bold} (eta. {eta})";
 
@@ -106,3 +228,111 @@ pub fn truncate_str(str: &str, size: usize) -> String {
     truncated.push_str("...");
     truncated
 }
+
+/// Run `op`, trying again up to `retries` times while it fails with a transient IO error.
+/// Waits 100 ms before the first retry and doubles it every time. Other errors, decode
+/// failures included, are returned right away
+pub fn retry_io<T>(retries: u32, path: &Path, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+
+    loop {
+        match op() {
+            Err(err) if attempt < retries && is_transient(&err) => {
+                let delay = Duration::from_millis(100 << attempt.min(10));
+                attempt += 1;
+                debug!("IO error on {path:?}, retry {attempt}/{retries} in {delay:?}: {err}");
+                sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// IO errors worth retrying, the ones network filesystems and file locks produce
+fn is_transient(err: &Report) -> bool {
+    err.chain().any(|cause| {
+        let io = cause
+            .downcast_ref::<io::Error>()
+            .or(match cause.downcast_ref() {
+                Some(ImageError::IoError(io)) => Some(io),
+                _ => None,
+            });
+
+        io.is_some_and(|io| {
+            // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+            (cfg!(windows) && matches!(io.raw_os_error(), Some(32 | 33)))
+                || matches!(
+                    io.kind(),
+                    ErrorKind::Interrupted
+                        | ErrorKind::WouldBlock
+                        | ErrorKind::TimedOut
+                        | ErrorKind::ResourceBusy
+                        | ErrorKind::StaleNetworkFileHandle
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::ConnectionAborted
+                )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use image::RgbImage;
+
+    use super::*;
+
+    #[test]
+    fn input_format_only_covers_unknown_extensions_in_directories() {
+        let dir = env::temp_dir().join(format!("avif-converter-{}-scan", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        RgbImage::new(32, 16).save(dir.join("photo.png")).unwrap();
+        RgbImage::new(32, 16)
+            .save_with_format(dir.join("scan"), ImageFormat::Jpeg)
+            .unwrap();
+        fs::write(dir.join(".avifconvert.toml"), "quality = 50\n").unwrap();
+
+        let mut images = parse_files(std::slice::from_ref(&dir), false, Some(ImageFormat::Jpeg));
+        images.sort_by(|a, b| a.metadata.filename.cmp(&b.metadata.filename));
+        let found: Vec<_> = images
+            .iter_mut()
+            .map(|image| {
+                let decoded = image.load_image_data(None, false, 0).is_ok();
+                (image.metadata.filename.clone(), image.format, decoded)
+            })
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            found,
+            [
+                ("photo.png".to_string(), ImageFormat::Png, true),
+                ("scan".to_string(), ImageFormat::Jpeg, true),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_hook_never_runs_file_names() {
+        let dir = env::temp_dir().join(format!("avif-converter-{}-hook", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let input = dir.join("it's $(touch pwned).png");
+        let output = dir.join("copy.png");
+        fs::write(&input, b"source").unwrap();
+
+        // Both the bare and the quoted placeholder, as the old docs suggested
+        run_exec_hook("cp {input} '{output}'", &input, &output, 0.5);
+
+        let copied = fs::read(&output);
+        // A substituted name would run `touch` in the working directory
+        let pwned = Path::new("pwned").exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(copied.unwrap(), b"source");
+        assert!(!pwned);
+    }
+}